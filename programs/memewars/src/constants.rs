@@ -1,10 +1,3 @@
-/// Trạng thái của cuộc chiến
-pub mod battle_status {
-    pub const ACTIVE: u8 = 0;
-    pub const SETTLED: u8 = 1;
-    pub const CANCELLED: u8 = 2;
-}
-
 /// Phe trong cuộc chiến
 pub mod team {
     pub const NONE: u8 = 0;
@@ -12,43 +5,162 @@ pub mod team {
     pub const TEAM_B: u8 = 2;
 }
 
+/// Loại cuộc chiến
+pub mod battle_kind {
+    /// Token A đấu với token B, phe có % growth cao hơn thắng
+    pub const HEAD_TO_HEAD: u8 = 0;
+
+    /// Đoán khoảng tăng trưởng của một token, mỗi bracket là một phe
+    pub const BRACKET: u8 = 1;
+}
+
+/// Lý do battle bị hủy
+pub mod cancel_reason {
+    /// Battle chưa bị hủy
+    pub const NONE: u8 = 0;
+
+    /// Authority hủy battle
+    pub const AUTHORITY: u8 = 1;
+
+    /// Battle lên lịch nhưng không ai gọi `start_battle` trong START_WINDOW
+    pub const NEVER_STARTED: u8 = 2;
+
+    /// Không settle được trong SETTLEMENT_GRACE_PERIOD sau end_time và admin không
+    /// force_resolve trong FORCE_RESOLVE_WINDOW tiếp theo
+    /// (authority offline hoặc oracle stale suốt thời gian đó)
+    pub const SETTLEMENT_TIMEOUT: u8 = 3;
+
+    /// Không đủ người tham gia khi settle (battle một phía hoặc dưới ngưỡng)
+    pub const LOW_PARTICIPATION: u8 = 4;
+
+    /// Circuit breaker bị kích hoạt và admin không xử lý trước review deadline
+    pub const REVIEW_TIMEOUT: u8 = 5;
+
+    /// Admin kích hoạt emergency unwind (lending protocol gặp sự cố)
+    /// Người chơi rút phần pro-rata của số tiền thu hồi được, có thể thấp hơn principal
+    pub const EMERGENCY_UNWIND: u8 = 6;
+}
+
+/// Lý do battle cần admin xem xét khi settle (circuit breaker)
+pub mod review_reason {
+    /// Không cần xem xét
+    pub const NONE: u8 = 0;
+
+    /// Growth vượt quá giới hạn của battle (nhiều khả năng oracle lỗi / đổi decimals)
+    pub const GROWTH_LIMIT: u8 = 1;
+
+    /// Giá cuối lệch quá xa EMA price của Pyth
+    pub const EMA_DIVERGENCE: u8 = 2;
+}
+
+/// Bracket battle settings
+pub mod brackets {
+    /// Số bracket tối thiểu (ví dụ: tăng / giảm)
+    pub const MIN_BRACKETS: usize = 2;
+
+    /// Số bracket tối đa trong một battle
+    /// Bracket i tương ứng với team i (1-based), mỗi bracket có vault riêng
+    pub const MAX_BRACKETS: usize = 8;
+}
+
+/// Keeper attestation settings
+pub mod keepers {
+    /// Số keeper tối đa trong một KeeperSet
+    pub const MAX_KEEPERS: usize = 10;
+}
+
 /// Pyth Oracle Price Feed IDs (Mainnet)
 /// Tham khảo: https://pyth.network/price-feeds
 pub mod pyth {
     
+    /// Pyth Solana Receiver program, owner của mọi price feed và price update (`PriceUpdateV2`)
+    pub const PYTH_PROGRAM_ID: &str = "rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LSSx";
+    
+    /// Pyth Push Oracle program, price feed account của battle là PDA
+    /// `[shard_id (u16 LE), feed_id]` của program này
+    pub const PUSH_ORACLE_PROGRAM_ID: &str = "pythWSnswVUd12oZpeFP8e9CVaEqJg25g1Vtc2biRsT";
+    
     /// SOL/USD Price Feed ID
-    pub const SOL_USD_PRICE_FEED: &str = "H6ARHf6YXhGYeQfUzQNGk6rDNnLBQKrenN712K4AQJEG";
+    pub const SOL_USD_FEED_ID: &str = "ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d";
     
-    /// BONK/USD Price Feed ID  
-    pub const BONK_USD_PRICE_FEED: &str = "8ihFLu5FimgTQ1Unh4dVyEHUGodJ5gJQCrQf4KUVB9bN";
+    /// BONK/USD Price Feed ID
+    pub const BONK_USD_FEED_ID: &str = "72b021217ca3fe68922a19aaf990109cb9d84e9ad004b4d2025ad6f529314419";
     
     /// WIF/USD Price Feed ID
-    pub const WIF_USD_PRICE_FEED: &str = "6ABgrEZk8urs6kJ1JNdC1sspH5zKXRqxy8sg3ZG2cQps";
+    pub const WIF_USD_FEED_ID: &str = "4ca4beeca86f0d164160323817a4e42b10010a724c2217c6ee41b54cd4cc61fc";
     
     /// POPCAT/USD Price Feed ID
-    pub const POPCAT_USD_PRICE_FEED: &str = "5SLwNNEXzgUJ4qjNYFKYxVKJVdrmMPdrP3sxyAj8UHbJ";
+    pub const POPCAT_USD_FEED_ID: &str = "b9312a7ee50e189ef045aa3c7842e099b061bd9bdc99ac645956c3b660dc8cce";
     
     /// Maximum staleness for price feeds (in seconds)
     /// Giá cũ hơn 60 giây sẽ không được chấp nhận
     pub const MAX_PRICE_AGE_SECONDS: u64 = 60;
     
+    /// Giá dùng để settle phải được publish trong ±60 giây quanh end_time
+    pub const SETTLEMENT_PRICE_TOLERANCE_SECONDS: u64 = 60;
+    
     /// Confidence ratio threshold (5% = 500 basis points)
     /// Nếu confidence interval > 5% của price thì reject
     pub const MAX_CONFIDENCE_RATIO_BPS: u64 = 500;
 }
 
+/// Settlement scoring settings
+pub mod scoring {
+    /// Growth tuyến tính: (final - initial) / initial
+    pub const LINEAR: u8 = 0;
+    
+    /// Log return: ln(final / initial), cân bằng hơn khi giá biến động mạnh
+    pub const LOG_RETURN: u8 = 1;
+    
+    /// Handicap tối đa (100% = 10000 basis points)
+    /// Handicap được cộng vào growth của token B trước khi so sánh
+    pub const MAX_HANDICAP_BPS: i64 = 10_000;
+    
+    /// Draw band tối đa (10% = 1000 basis points)
+    /// Chênh lệch growth nằm trong draw band thì tính là hòa
+    pub const MAX_TIE_TOLERANCE_BPS: u64 = 1_000;
+}
+
 /// Protocol fee settings
 pub mod fees {
-    /// Protocol fee trên yield (5% = 500 basis points)
+    /// Protocol fee mặc định trên yield (5% = 500 basis points)
     /// Phí này được trừ từ tổng yield trước khi chia cho winners
+    /// Admin thay đổi qua `update_config`, mỗi battle giữ giá trị lúc tạo
     pub const PROTOCOL_FEE_BPS: u64 = 500;
     
+    /// Protocol fee tối đa admin có thể đặt (20%)
+    pub const MAX_PROTOCOL_FEE_BPS: u64 = 2_000;
+    
     /// Basis points divisor
     pub const BPS_DIVISOR: u64 = 10_000;
     
-    /// Early withdrawal penalty (1% = 100 basis points)
+    /// Early withdrawal penalty mặc định (1% = 100 basis points)
     /// Phạt nếu rút sớm trước khi battle kết thúc
     pub const EARLY_WITHDRAWAL_PENALTY_BPS: u64 = 100;
+    
+    /// Early withdrawal penalty tối đa admin có thể đặt (5%)
+    pub const MAX_EARLY_WITHDRAWAL_PENALTY_BPS: u64 = 500;
+    
+    /// Bounty cho keeper gọi settle (1% yield = 100 basis points)
+    /// Trích từ protocol fee, không ảnh hưởng phần yield của winners
+    pub const KEEPER_BOUNTY_BPS: u64 = 100;
+    
+    /// Bounty tối đa cho keeper (0.01 SOL)
+    pub const MAX_KEEPER_BOUNTY: u64 = 10_000_000;
+    
+    /// Bond mà challenger phải đặt khi dispute kết quả (0.1 SOL)
+    /// Mất bond nếu kết quả của challenger bị dispute thành công sau đó
+    pub const DISPUTE_BOND: u64 = 100_000_000;
+}
+
+/// Insurance fund bảo vệ principal của người chơi
+pub mod insurance {
+    /// Tỷ lệ protocol fee mặc định chuyển vào insurance fund (20% = 2000 bps)
+    pub const FEE_SHARE_BPS: u64 = 2_000;
+    
+    /// Coverage tối đa mặc định cho mỗi battle (10% principal = 1000 bps)
+    /// Insurance fund chỉ bù thiếu hụt trong giới hạn này, phần còn lại bị haircut
+    pub const MAX_COVERAGE_BPS: u64 = 1_000;
 }
 
 /// Time constants
@@ -59,8 +171,29 @@ pub mod time {
     /// Maximum battle duration (30 days in seconds)
     pub const MAX_BATTLE_DURATION: i64 = 2_592_000;
     
+    /// Crank `start_battle` phải chạy trong 10 phút sau start_time để giá ban đầu
+    /// sát với start_time, quá hạn thì bất kỳ ai cũng có thể hủy battle
+    pub const START_WINDOW: i64 = 600;
+    
     /// Grace period after battle ends for settlement (1 hour)
     pub const SETTLEMENT_GRACE_PERIOD: i64 = 3_600;
+    
+    /// Thời gian dispute kết quả đã đề xuất (1 hour)
+    pub const DISPUTE_WINDOW: i64 = 3_600;
+    
+    /// Thời gian admin xử lý battle bị circuit breaker (3 days), quá hạn thì hủy
+    pub const REVIEW_PERIOD: i64 = 259_200;
+    
+    /// Timelock trước khi kết quả do admin force_resolve được finalize (2 days)
+    /// Trong thời gian này người chơi rút lại tiền không mất penalty
+    pub const FORCE_RESOLVE_TIMELOCK: i64 = 172_800;
+    
+    /// Sau settlement deadline, admin có 1 ngày độc quyền force_resolve trước khi
+    /// bất kỳ ai cũng có thể hủy battle (tránh phe thua hủy trước admin)
+    pub const FORCE_RESOLVE_WINDOW: i64 = 86_400;
+    
+    /// Battle có thể được lên lịch trước tối đa 30 ngày
+    pub const MAX_SCHEDULE_AHEAD: i64 = 2_592_000;
 }
 
 /// Lending protocol addresses (Mainnet)
@@ -97,5 +230,9 @@ pub mod seeds {
     pub const VAULT: &[u8] = b"vault";
     pub const TICKET_MINT: &[u8] = b"ticket_mint";
     pub const TICKET_MINT_AUTHORITY: &[u8] = b"ticket_mint_authority";
+    pub const PROTOCOL_STATE: &[u8] = b"protocol_state";
     pub const PROTOCOL_TREASURY: &[u8] = b"protocol_treasury";
+    pub const KEEPER_SET: &[u8] = b"keeper_set";
+    pub const ATTESTATIONS: &[u8] = b"attestations";
+    pub const INSURANCE_FUND: &[u8] = b"insurance_fund";
 }
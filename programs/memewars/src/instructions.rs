@@ -1,10 +1,347 @@
 use anchor_lang::prelude::*;
 use anchor_spl::token::{self, Mint, MintTo, Token, TokenAccount, Burn};
 
-use crate::constants::{self, battle_status, team, fees};
+use crate::constants::{self, battle_kind, brackets, team, fees, insurance};
+use crate::lending;
 use crate::pyth;
 use crate::state::*;
 
+// ============================================================================
+// INITIALIZE - Tạo ProtocolState singleton
+// ============================================================================
+
+/// Khởi tạo protocol: tạo ProtocolState, ProtocolTreasury và InsuranceFund PDA (chỉ một lần)
+pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    let treasury = &mut ctx.accounts.treasury;
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
+    
+    treasury.total_collected = 0;
+    treasury.total_withdrawn = 0;
+    treasury.bump = ctx.bumps.treasury;
+    
+    insurance_fund.total_deposited = 0;
+    insurance_fund.total_paid_out = 0;
+    insurance_fund.bump = ctx.bumps.insurance_fund;
+    
+    protocol_state.authority = ctx.accounts.authority.key();
+    protocol_state.pending_authority = Pubkey::default();
+    protocol_state.paused = false;
+    protocol_state.set_roles(&RoleParams {
+        battle_creator: Pubkey::default(),
+        settler: ctx.accounts.authority.key(),
+        treasury_manager: ctx.accounts.authority.key(),
+    })?;
+    protocol_state.treasury = treasury.key();
+    protocol_state.total_battles = 0;
+    protocol_state.total_tvl = 0;
+    protocol_state.total_fees_collected = 0;
+    protocol_state.insurance_fund = insurance_fund.key();
+    protocol_state.insurance_balance = 0;
+    protocol_state.set_config(&ConfigParams {
+        protocol_fee_bps: fees::PROTOCOL_FEE_BPS,
+        early_withdrawal_penalty_bps: fees::EARLY_WITHDRAWAL_PENALTY_BPS,
+        insurance_fee_share_bps: insurance::FEE_SHARE_BPS,
+        max_coverage_bps: insurance::MAX_COVERAGE_BPS,
+    })?;
+    protocol_state.bump = ctx.bumps.protocol_state;
+    
+    msg!(
+        "MemeWars protocol initialized: authority {} | treasury {} | insurance fund {}",
+        protocol_state.authority,
+        protocol_state.treasury,
+        protocol_state.insurance_fund
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    /// Phải là upgrade authority của program, tránh người lạ chiếm quyền admin
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Program memewars, dùng để xác định ProgramData
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Memewars>,
+    
+    /// ProgramData của program (BPF upgradeable loader)
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key())
+            @ MemeWarsError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = ProtocolState::LEN,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = ProtocolTreasury::LEN,
+        seeds = [constants::seeds::PROTOCOL_TREASURY],
+        bump
+    )]
+    pub treasury: Account<'info, ProtocolTreasury>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = InsuranceFund::LEN,
+        seeds = [constants::seeds::INSURANCE_FUND],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+// ============================================================================
+// UPDATE CONFIG - Admin thay đổi tham số phí
+// ============================================================================
+
+/// Cập nhật protocol fee và early withdrawal penalty cho các battle tạo sau này,
+/// cùng tỷ lệ fee vào insurance fund và coverage tối đa mỗi battle
+pub fn update_config(ctx: Context<UpdateConfig>, config: ConfigParams) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    
+    protocol_state.set_config(&config)?;
+    
+    msg!(
+        "Protocol config updated: fee {} bps, early withdrawal penalty {} bps, insurance share {} bps, max coverage {} bps",
+        config.protocol_fee_bps,
+        config.early_withdrawal_penalty_bps,
+        config.insurance_fee_share_bps,
+        config.max_coverage_bps
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+// ============================================================================
+// ROLES & AUTHORITY - Phân quyền và chuyển giao admin
+// ============================================================================
+
+/// Admin đặt các role: battle creator, settler, treasury manager
+pub fn set_roles(ctx: Context<SetRoles>, roles: RoleParams) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    
+    protocol_state.set_roles(&roles)?;
+    
+    msg!(
+        "Protocol roles updated: battle creator {} | settler {} | treasury manager {}",
+        roles.battle_creator,
+        roles.settler,
+        roles.treasury_manager
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SetRoles<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+/// Bước 1: admin đề xuất admin mới (Pubkey::default() để hủy đề xuất)
+pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    
+    protocol_state.pending_authority = new_authority;
+    
+    msg!(
+        "Protocol authority transfer proposed: {} -> {}",
+        protocol_state.authority,
+        new_authority
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+/// Bước 2: admin mới ký để nhận quyền
+pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    let previous = protocol_state.authority;
+    
+    protocol_state.authority = ctx.accounts.new_authority.key();
+    protocol_state.pending_authority = Pubkey::default();
+    
+    msg!(
+        "Protocol authority transferred: {} -> {}",
+        previous,
+        protocol_state.authority
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    pub new_authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        constraint = protocol_state.pending_authority == new_authority.key() @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+/// Đổi authority của một battle (authority hiện tại hoặc admin của protocol)
+pub fn transfer_battle_authority(
+    ctx: Context<TransferBattleAuthority>,
+    new_authority: Pubkey,
+) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    
+    require!(new_authority != Pubkey::default(), MemeWarsError::InvalidRole);
+    
+    let previous = battle.authority;
+    battle.authority = new_authority;
+    
+    msg!(
+        "Battle {} authority rotated by {}: {} -> {}",
+        battle.battle_id,
+        ctx.accounts.authority.key(),
+        previous,
+        new_authority
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct TransferBattleAuthority<'info> {
+    /// Authority hiện tại của battle hoặc admin của protocol
+    pub authority: Signer<'info>,
+    
+    /// Protocol state (roles)
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+}
+
+// ============================================================================
+// PAUSE - Emergency kill switch
+// ============================================================================
+
+/// Admin pause/unpause toàn protocol: chặn tạo battle và deposit
+/// Withdraw và claim không bị ảnh hưởng
+pub fn set_protocol_paused(ctx: Context<SetProtocolPaused>, paused: bool) -> Result<()> {
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    
+    protocol_state.paused = paused;
+    
+    msg!(
+        "Protocol {} by {}",
+        if paused { "paused" } else { "unpaused" },
+        ctx.accounts.authority.key()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SetProtocolPaused<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+/// Pause/unpause một battle (authority của battle hoặc admin của protocol)
+/// Chặn deposit, withdraw và claim không bị ảnh hưởng
+pub fn set_battle_paused(ctx: Context<SetBattlePaused>, paused: bool) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    
+    battle.paused = paused;
+    
+    msg!(
+        "Battle {} {} by {}",
+        battle.battle_id,
+        if paused { "paused" } else { "unpaused" },
+        ctx.accounts.authority.key()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SetBattlePaused<'info> {
+    /// Authority của battle hoặc admin của protocol
+    pub authority: Signer<'info>,
+    
+    /// Protocol state (roles)
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+}
+
 // ============================================================================
 // CREATE BATTLE
 // ============================================================================
@@ -14,41 +351,52 @@ pub fn create_battle(
     ctx: Context<CreateBattle>,
     battle_id: u64,
     duration_seconds: i64,
+    scoring: ScoringParams,
+    schedule: ScheduleParams,
+    safety: SafetyParams,
 ) -> Result<()> {
     let battle = &mut ctx.accounts.battle;
     let clock = Clock::get()?;
     
     // Validate duration
+    validate_duration(duration_seconds)?;
+    
+    // Validate schedule
+    let (start_time, scheduled) = resolve_start_time(&schedule, clock.unix_timestamp)?;
+    validate_lock_period(schedule.lock_period_seconds, duration_seconds)?;
+    
+    // Validate handicap
     require!(
-        duration_seconds >= constants::time::MIN_BATTLE_DURATION,
-        MemeWarsError::InvalidDuration
+        (-constants::scoring::MAX_HANDICAP_BPS..=constants::scoring::MAX_HANDICAP_BPS)
+            .contains(&scoring.handicap_bps),
+        MemeWarsError::InvalidHandicap
     );
+    
+    // Validate draw band
     require!(
-        duration_seconds <= constants::time::MAX_BATTLE_DURATION,
-        MemeWarsError::InvalidDuration
+        scoring.tie_tolerance_bps <= constants::scoring::MAX_TIE_TOLERANCE_BPS,
+        MemeWarsError::InvalidTieTolerance
     );
     
+    // Validate scoring mode
+    validate_scoring_mode(scoring.scoring_mode)?;
+    
     // Get initial prices from Pyth
-    let price_a = pyth::get_pyth_price(&ctx.accounts.price_feed_a)?;
-    let price_b = pyth::get_pyth_price(&ctx.accounts.price_feed_b)?;
+    // Battle lên lịch trước sẽ chụp giá khi `start_battle` được gọi
+    let (price_a, price_b) = if scheduled {
+        (0, 0)
+    } else {
+        let price_a = pyth::get_pyth_price(&ctx.accounts.price_feed_a)?;
+        let price_b = pyth::get_pyth_price(&ctx.accounts.price_feed_b)?;
+        
+        // Giá ban đầu phải dương để tính growth
+        require!(price_a > 0 && price_b > 0, MemeWarsError::InvalidPriceFeed);
+        (price_a, price_b)
+    };
     
     // Derive vault PDAs
-    let (vault_a_pda, _) = Pubkey::find_program_address(
-        &[
-            constants::seeds::VAULT,
-            battle_id.to_le_bytes().as_ref(),
-            team::TEAM_A.to_le_bytes().as_ref(),
-        ],
-        ctx.program_id,
-    );
-    let (vault_b_pda, _) = Pubkey::find_program_address(
-        &[
-            constants::seeds::VAULT,
-            battle_id.to_le_bytes().as_ref(),
-            team::TEAM_B.to_le_bytes().as_ref(),
-        ],
-        ctx.program_id,
-    );
+    let vault_a_pda = vault_address(battle_id, team::TEAM_A, ctx.program_id);
+    let vault_b_pda = vault_address(battle_id, team::TEAM_B, ctx.program_id);
     
     // Initialize battle state
     battle.battle_id = battle_id;
@@ -57,31 +405,87 @@ pub fn create_battle(
     battle.token_b = ctx.accounts.token_b.key();
     battle.price_feed_a = ctx.accounts.price_feed_a.key();
     battle.price_feed_b = ctx.accounts.price_feed_b.key();
+    battle.feed_id_a = pyth::get_feed_id(&ctx.accounts.price_feed_a)?;
+    battle.feed_id_b = pyth::get_feed_id(&ctx.accounts.price_feed_b)?;
     battle.initial_price_a = price_a;
     battle.initial_price_b = price_b;
     battle.final_price_a = None;
     battle.final_price_b = None;
-    battle.start_time = clock.unix_timestamp;
-    battle.end_time = clock.unix_timestamp + duration_seconds;
+    battle.start_time = start_time;
+    battle.end_time = start_time + duration_seconds;
     battle.total_staked_a = 0;
     battle.total_staked_b = 0;
-    battle.status = battle_status::ACTIVE;
+    battle.phase = if scheduled { BattlePhase::Scheduled } else { BattlePhase::Open };
     battle.winner = team::NONE;
     battle.vault_a = vault_a_pda;
     battle.vault_b = vault_b_pda;
     battle.total_yield_collected = 0;
     battle.winner_yield = 0;
     battle.protocol_fee_collected = 0;
+    battle.kind = battle_kind::HEAD_TO_HEAD;
+    battle.bracket_count = 0;
+    battle.bracket_bounds = [0; brackets::MAX_BRACKETS - 1];
+    battle.bracket_staked = [0; brackets::MAX_BRACKETS];
+    battle.stake_seconds_a = 0;
+    battle.stake_seconds_b = 0;
+    battle.bracket_stake_seconds = [0; brackets::MAX_BRACKETS];
+    battle.handicap_bps = scoring.handicap_bps;
+    battle.tie_tolerance_bps = scoring.tie_tolerance_bps;
+    battle.confidence_ties = scoring.confidence_ties;
+    battle.scoring_mode = scoring.scoring_mode;
+    battle.pre_season_deposits = schedule.pre_season_deposits;
+    battle.lock_period_seconds = schedule.lock_period_seconds;
+    battle.final_conf_a = 0;
+    battle.final_conf_b = 0;
+    battle.cancel_reason = constants::cancel_reason::NONE;
+    battle.settled_by = Pubkey::default();
+    battle.keeper_bounty = 0;
+    battle.dispute_deadline = 0;
+    battle.dispute_bond = 0;
+    battle.dispute_count = 0;
+    battle.min_team_stake = safety.min_team_stake;
+    battle.max_growth_bps = safety.max_growth_bps;
+    battle.max_ema_divergence_bps = safety.max_ema_divergence_bps;
+    battle.review_reason = constants::review_reason::NONE;
+    battle.review_deadline = 0;
+    battle.keeper_set = ctx
+        .accounts
+        .keeper_set
+        .as_ref()
+        .map(|keeper_set| keeper_set.key())
+        .unwrap_or_default();
+    battle.force_resolved = false;
+    battle.protocol_fee_paid = 0;
+    battle.paused = false;
+    battle.recovery_bps = fees::BPS_DIVISOR;
+    battle.insurance_payout = 0;
     battle.bump = ctx.bumps.battle;
     
+    // Snapshot tham số phí: thay đổi config sau này không ảnh hưởng battle đang chạy
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    battle.protocol_fee_bps = protocol_state.protocol_fee_bps;
+    battle.early_withdrawal_penalty_bps = protocol_state.early_withdrawal_penalty_bps;
+    protocol_state.total_battles = protocol_state
+        .total_battles
+        .checked_add(1)
+        .ok_or(MemeWarsError::Overflow)?;
+    
     msg!(
-        "Battle {} created: {} vs {} | Duration: {} seconds | Initial prices: A={}, B={}",
+        "Battle {} created: {} vs {} | Start: {} (scheduled: {}, pre-season deposits: {}) | Duration: {} seconds, lock: {} seconds | Initial prices: A={}, B={} | Handicap B: {} bps | Draw band: {} bps, confidence ties: {} | Scoring mode: {}",
         battle_id,
         ctx.accounts.token_a.key(),
         ctx.accounts.token_b.key(),
+        start_time,
+        scheduled,
+        schedule.pre_season_deposits,
         duration_seconds,
+        schedule.lock_period_seconds,
         price_a,
-        price_b
+        price_b,
+        scoring.handicap_bps,
+        scoring.tie_tolerance_bps,
+        scoring.confidence_ties,
+        scoring.scoring_mode
     );
     
     Ok(())
@@ -102,6 +506,16 @@ pub struct CreateBattle<'info> {
     )]
     pub battle: Account<'info, BattleState>,
     
+    /// Protocol state (counters global, role battle_creator)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        constraint = protocol_state.can_create_battles(&authority.key()) @ MemeWarsError::Unauthorized,
+        constraint = !protocol_state.paused @ MemeWarsError::ProtocolPaused
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
     /// Token A mint (e.g., BONK)
     /// CHECK: Just storing the pubkey
     pub token_a: UncheckedAccount<'info>,
@@ -118,49 +532,341 @@ pub struct CreateBattle<'info> {
     /// CHECK: Pyth price feed account
     pub price_feed_b: UncheckedAccount<'info>,
     
+    /// KeeperSet attest kết quả thay cho propose/dispute (tùy chọn)
+    pub keeper_set: Option<Account<'info, KeeperSet>>,
+    
     pub system_program: Program<'info, System>,
 }
 
 // ============================================================================
-// DEPOSIT
+// CREATE BRACKET BATTLE - Đoán khoảng tăng trưởng của một token
 // ============================================================================
 
-/// Deposit SOL vào một cuộc chiến
-pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
+/// Tạo bracket battle: mỗi khoảng growth là một phe với vault riêng
+///
+/// `bracket_bounds` là các ranh giới growth (bps) tăng dần, N ranh giới tạo ra N + 1 bracket.
+/// Ví dụ [-2000, 0, 2000] tạo 4 bracket: <-20%, -20..0%, 0..20%, >=20%
+pub fn create_bracket_battle(
+    ctx: Context<CreateBracketBattle>,
+    battle_id: u64,
+    duration_seconds: i64,
+    bracket_bounds: Vec<i64>,
+    scoring_mode: u8,
+    schedule: ScheduleParams,
+    safety: SafetyParams,
+) -> Result<()> {
     let battle = &mut ctx.accounts.battle;
-    let user_state = &mut ctx.accounts.user_state;
-    let vault = &mut ctx.accounts.vault;
     let clock = Clock::get()?;
-
-    // Kiểm tra cuộc chiến đang active
-    require!(battle.is_active(), MemeWarsError::BattleNotActive);
     
-    // Kiểm tra team hợp lệ
+    // Validate duration
+    validate_duration(duration_seconds)?;
+    
+    // Validate schedule
+    let (start_time, scheduled) = resolve_start_time(&schedule, clock.unix_timestamp)?;
+    validate_lock_period(schedule.lock_period_seconds, duration_seconds)?;
+    
+    // Validate scoring mode (bounds được hiểu theo cùng đơn vị với điểm growth)
+    validate_scoring_mode(scoring_mode)?;
+    
+    // Validate brackets: đủ số lượng và tăng dần nghiêm ngặt
+    let bracket_count = bracket_bounds.len() + 1;
     require!(
-        team == constants::team::TEAM_A || team == constants::team::TEAM_B,
-        MemeWarsError::InvalidTeam
+        (brackets::MIN_BRACKETS..=brackets::MAX_BRACKETS).contains(&bracket_count),
+        MemeWarsError::InvalidBrackets
     );
-
-    // Kiểm tra thời gian còn trong phạm vi cuộc chiến
     require!(
-        clock.unix_timestamp >= battle.start_time && clock.unix_timestamp < battle.end_time,
-        MemeWarsError::BattleTimeExpired
+        bracket_bounds.windows(2).all(|pair| pair[0] < pair[1]),
+        MemeWarsError::InvalidBrackets
     );
-
-    // Kiểm tra vault phù hợp với team
-    let expected_vault = if team == constants::team::TEAM_A {
-        battle.vault_a
+    
+    // Get initial price from Pyth (battle lên lịch trước chụp giá khi bắt đầu)
+    let price = if scheduled {
+        0
     } else {
-        battle.vault_b
+        let price = pyth::get_pyth_price(&ctx.accounts.price_feed)?;
+        require!(price > 0, MemeWarsError::InvalidPriceFeed);
+        price
     };
-    require!(
-        vault.key() == expected_vault,
-        MemeWarsError::InvalidVault
+    
+    let mut bounds = [0i64; brackets::MAX_BRACKETS - 1];
+    bounds[..bracket_bounds.len()].copy_from_slice(&bracket_bounds);
+    
+    // Initialize battle state
+    // Bracket battle chỉ theo dõi một token, các field của token B để trống
+    battle.battle_id = battle_id;
+    battle.authority = ctx.accounts.authority.key();
+    battle.token_a = ctx.accounts.token.key();
+    battle.token_b = Pubkey::default();
+    battle.price_feed_a = ctx.accounts.price_feed.key();
+    battle.price_feed_b = Pubkey::default();
+    battle.feed_id_a = pyth::get_feed_id(&ctx.accounts.price_feed)?;
+    battle.feed_id_b = [0; 32];
+    battle.initial_price_a = price;
+    battle.initial_price_b = 0;
+    battle.final_price_a = None;
+    battle.final_price_b = None;
+    battle.start_time = start_time;
+    battle.end_time = start_time + duration_seconds;
+    battle.total_staked_a = 0;
+    battle.total_staked_b = 0;
+    battle.phase = if scheduled { BattlePhase::Scheduled } else { BattlePhase::Open };
+    battle.winner = team::NONE;
+    battle.vault_a = vault_address(battle_id, team::TEAM_A, ctx.program_id);
+    battle.vault_b = vault_address(battle_id, team::TEAM_B, ctx.program_id);
+    battle.total_yield_collected = 0;
+    battle.winner_yield = 0;
+    battle.protocol_fee_collected = 0;
+    battle.kind = battle_kind::BRACKET;
+    battle.bracket_count = bracket_count as u8;
+    battle.bracket_bounds = bounds;
+    battle.bracket_staked = [0; brackets::MAX_BRACKETS];
+    battle.stake_seconds_a = 0;
+    battle.stake_seconds_b = 0;
+    battle.bracket_stake_seconds = [0; brackets::MAX_BRACKETS];
+    battle.handicap_bps = 0;
+    battle.tie_tolerance_bps = 0;
+    battle.confidence_ties = false;
+    battle.scoring_mode = scoring_mode;
+    battle.pre_season_deposits = schedule.pre_season_deposits;
+    battle.lock_period_seconds = schedule.lock_period_seconds;
+    battle.final_conf_a = 0;
+    battle.final_conf_b = 0;
+    battle.cancel_reason = constants::cancel_reason::NONE;
+    battle.settled_by = Pubkey::default();
+    battle.keeper_bounty = 0;
+    battle.dispute_deadline = 0;
+    battle.dispute_bond = 0;
+    battle.dispute_count = 0;
+    battle.min_team_stake = safety.min_team_stake;
+    battle.max_growth_bps = safety.max_growth_bps;
+    battle.max_ema_divergence_bps = safety.max_ema_divergence_bps;
+    battle.review_reason = constants::review_reason::NONE;
+    battle.review_deadline = 0;
+    battle.keeper_set = ctx
+        .accounts
+        .keeper_set
+        .as_ref()
+        .map(|keeper_set| keeper_set.key())
+        .unwrap_or_default();
+    battle.force_resolved = false;
+    battle.protocol_fee_paid = 0;
+    battle.paused = false;
+    battle.recovery_bps = fees::BPS_DIVISOR;
+    battle.insurance_payout = 0;
+    battle.bump = ctx.bumps.battle;
+    
+    // Snapshot tham số phí: thay đổi config sau này không ảnh hưởng battle đang chạy
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    battle.protocol_fee_bps = protocol_state.protocol_fee_bps;
+    battle.early_withdrawal_penalty_bps = protocol_state.early_withdrawal_penalty_bps;
+    protocol_state.total_battles = protocol_state
+        .total_battles
+        .checked_add(1)
+        .ok_or(MemeWarsError::Overflow)?;
+    
+    msg!(
+        "Bracket battle {} created: {} | {} brackets, bounds {:?} bps | Start: {} (scheduled: {}, pre-season deposits: {}) | Duration: {} seconds, lock: {} seconds | Initial price: {} | Scoring mode: {}",
+        battle_id,
+        ctx.accounts.token.key(),
+        bracket_count,
+        bracket_bounds,
+        start_time,
+        scheduled,
+        schedule.pre_season_deposits,
+        duration_seconds,
+        schedule.lock_period_seconds,
+        price,
+        scoring_mode
     );
+    
+    Ok(())
+}
 
-    // Transfer SOL từ user vào vault
-    let cpi_context = CpiContext::new(
-        ctx.accounts.system_program.to_account_info(),
+#[derive(Accounts)]
+#[instruction(battle_id: u64)]
+pub struct CreateBracketBattle<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = BattleState::LEN,
+        seeds = [constants::seeds::BATTLE, battle_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Protocol state (counters global, role battle_creator)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        constraint = protocol_state.can_create_battles(&authority.key()) @ MemeWarsError::Unauthorized,
+        constraint = !protocol_state.paused @ MemeWarsError::ProtocolPaused
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    /// Token mint được dự đoán
+    /// CHECK: Just storing the pubkey
+    pub token: UncheckedAccount<'info>,
+    
+    /// Pyth price feed của token
+    /// CHECK: Pyth price feed account
+    pub price_feed: UncheckedAccount<'info>,
+    
+    /// KeeperSet attest kết quả thay cho propose/dispute (tùy chọn)
+    pub keeper_set: Option<Account<'info, KeeperSet>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+// ============================================================================
+// START BATTLE - Crank bắt đầu battle đã lên lịch
+// ============================================================================
+
+/// Phase cho phép start
+pub const START_PHASES: &[BattlePhase] = &[BattlePhase::Scheduled];
+
+/// Bắt đầu battle đã lên lịch: chụp giá ban đầu từ Pyth và mở battle
+/// Permissionless - bất kỳ ai cũng có thể gọi trong [start_time, start_time + START_WINDOW)
+pub fn start_battle(ctx: Context<StartBattle>) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let clock = Clock::get()?;
+    
+    // Kiểm tra battle đang chờ bắt đầu và crank còn trong START_WINDOW
+    // Quá hạn thì giá ban đầu không còn đại diện cho start_time, battle phải bị hủy
+    battle.require_phase(START_PHASES, clock.unix_timestamp)?;
+    require!(
+        clock.unix_timestamp >= battle.start_time,
+        MemeWarsError::BattleNotStarted
+    );
+    require!(
+        clock.unix_timestamp < battle.start_deadline(),
+        MemeWarsError::StartWindowExpired
+    );
+    
+    // Chụp giá ban đầu (bracket battle chỉ cần price feed A)
+    let price_a = pyth::get_pyth_price(&ctx.accounts.price_feed_a)?;
+    require!(price_a > 0, MemeWarsError::InvalidPriceFeed);
+    battle.initial_price_a = price_a;
+    
+    if !battle.is_bracket() {
+        let price_feed_b = ctx.accounts.price_feed_b
+            .as_ref()
+            .ok_or(MemeWarsError::InvalidPriceFeed)?;
+        let price_b = pyth::get_pyth_price(price_feed_b)?;
+        require!(price_b > 0, MemeWarsError::InvalidPriceFeed);
+        battle.initial_price_b = price_b;
+    }
+    
+    battle.transition(BattlePhase::Open)?;
+    
+    msg!(
+        "Battle {} started by {} | Initial prices: A={}, B={} | Total pre-season stake: {}",
+        battle.battle_id,
+        ctx.accounts.cranker.key(),
+        battle.initial_price_a,
+        battle.initial_price_b,
+        battle.total_staked()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct StartBattle<'info> {
+    /// Người gọi crank (bất kỳ ai)
+    pub cranker: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Pyth price feed for token A
+    /// CHECK: Pyth price feed account, phải khớp với feed đã lưu trong battle
+    #[account(address = battle.price_feed_a @ MemeWarsError::InvalidPriceFeed)]
+    pub price_feed_a: UncheckedAccount<'info>,
+    
+    /// Pyth price feed for token B (không cần cho bracket battle)
+    /// CHECK: Pyth price feed account, phải khớp với feed đã lưu trong battle
+    #[account(address = battle.price_feed_b @ MemeWarsError::InvalidPriceFeed)]
+    pub price_feed_b: Option<UncheckedAccount<'info>>,
+}
+
+// ============================================================================
+// DEPOSIT
+// ============================================================================
+
+/// Phase cho phép deposit
+pub const DEPOSIT_PHASES: &[BattlePhase] = &[BattlePhase::Open];
+
+/// Phase cho phép deposit khi battle bật pre-season deposits
+pub const PRE_SEASON_DEPOSIT_PHASES: &[BattlePhase] = &[BattlePhase::Scheduled, BattlePhase::Open];
+
+/// Deposit SOL vào một cuộc chiến
+pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let user_state = &mut ctx.accounts.user_state;
+    let vault = &mut ctx.accounts.vault;
+    let clock = Clock::get()?;
+
+    // Kiểm tra cuộc chiến đang nhận deposit
+    battle.sync_phase(clock.unix_timestamp)?;
+    let accepted_phases = if battle.pre_season_deposits {
+        PRE_SEASON_DEPOSIT_PHASES
+    } else {
+        DEPOSIT_PHASES
+    };
+    battle.require_phase(accepted_phases, clock.unix_timestamp)?;
+    
+    // Pre-season deposit dừng khi hết start window (battle không còn start được)
+    if battle.phase == BattlePhase::Scheduled {
+        require!(
+            clock.unix_timestamp < battle.start_deadline(),
+            MemeWarsError::StartWindowExpired
+        );
+    }
+    
+    // Kiểm tra team hợp lệ
+    require!(battle.is_valid_team(team), MemeWarsError::InvalidTeam);
+    
+    // Đã rút hoặc claim thì không stake lại vào cùng battle
+    require!(!user_state.claimed, MemeWarsError::AlreadyClaimed);
+
+    // Kiểm tra thời gian còn trong phạm vi cuộc chiến
+    // (pre-season deposit được nhận trước start_time)
+    require!(
+        clock.unix_timestamp < battle.end_time,
+        MemeWarsError::BattleTimeExpired
+    );
+    
+    // Không nhận deposit trong lock window trước end_time
+    require!(
+        !battle.is_locked(clock.unix_timestamp),
+        MemeWarsError::BattleLocked
+    );
+
+    // Kiểm tra vault phù hợp với team
+    // (các bracket còn lại được ràng buộc qua seeds của vault)
+    let expected_vault = match team {
+        constants::team::TEAM_A => Some(battle.vault_a),
+        constants::team::TEAM_B => Some(battle.vault_b),
+        _ => None,
+    };
+    if let Some(expected_vault) = expected_vault {
+        require!(
+            vault.key() == expected_vault,
+            MemeWarsError::InvalidVault
+        );
+    }
+
+    // Transfer SOL từ user vào vault
+    let cpi_context = CpiContext::new(
+        ctx.accounts.system_program.to_account_info(),
         anchor_lang::system_program::Transfer {
             from: ctx.accounts.user.to_account_info(),
             to: vault.to_account_info(),
@@ -168,6 +874,13 @@ pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
     );
     anchor_lang::system_program::transfer(cpi_context, amount)?;
 
+    // Stake theo thời gian mà deposit này đóng góp tới end_time
+    let stake_seconds = battle.stake_weight(amount, clock.unix_timestamp)?;
+
+    // Shares phát hành theo giá trị mỗi share trước khi nhận deposit này
+    let shares = vault.amount_to_shares(amount)?;
+    require!(shares > 0, MemeWarsError::InsufficientFunds);
+
     // Cập nhật UserState
     if user_state.amount_staked == 0 {
         // Lần đầu stake
@@ -175,7 +888,9 @@ pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
         user_state.battle_id = battle.battle_id;
         user_state.team = team;
         user_state.amount_staked = amount;
+        user_state.shares = shares;
         user_state.stake_time = clock.unix_timestamp;
+        user_state.stake_seconds = stake_seconds;
         user_state.claimed = false;
         user_state.reward_amount = 0;
         user_state.bump = ctx.bumps.user_state;
@@ -188,10 +903,16 @@ pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
         user_state.amount_staked = user_state.amount_staked
             .checked_add(amount)
             .ok_or(MemeWarsError::Overflow)?;
+        user_state.shares = user_state.shares
+            .checked_add(shares)
+            .ok_or(MemeWarsError::Overflow)?;
+        user_state.stake_seconds = user_state.stake_seconds
+            .checked_add(stake_seconds)
+            .ok_or(MemeWarsError::Overflow)?;
     }
 
     // Khởi tạo Vault nếu mới
-    if vault.total_amount == 0 && vault.lent_amount == 0 {
+    if vault.total_shares == 0 && vault.lent_amount == 0 {
         vault.battle_id = battle.battle_id;
         vault.team = team;
         vault.lending_position = None;
@@ -205,17 +926,18 @@ pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
     vault.total_amount = vault.total_amount
         .checked_add(amount)
         .ok_or(MemeWarsError::Overflow)?;
+    vault.total_shares = vault.total_shares
+        .checked_add(shares)
+        .ok_or(MemeWarsError::Overflow)?;
+    
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    protocol_state.total_tvl = protocol_state.total_tvl
+        .checked_add(amount)
+        .ok_or(MemeWarsError::Overflow)?;
 
     // Cập nhật BattleState
-    if team == constants::team::TEAM_A {
-        battle.total_staked_a = battle.total_staked_a
-            .checked_add(amount)
-            .ok_or(MemeWarsError::Overflow)?;
-    } else {
-        battle.total_staked_b = battle.total_staked_b
-            .checked_add(amount)
-            .ok_or(MemeWarsError::Overflow)?;
-    }
+    battle.add_team_stake(team, amount)?;
+    battle.add_team_stake_seconds(team, stake_seconds)?;
 
     // Mint ticket token cho người dùng
     let battle_id_bytes = battle.battle_id.to_le_bytes();
@@ -236,14 +958,16 @@ pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
     let cpi_program = ctx.accounts.token_program.to_account_info();
     let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
 
-    token::mint_to(cpi_ctx, amount)?;
+    token::mint_to(cpi_ctx, shares)?;
 
     msg!(
-        "Deposit: User {} staked {} lamports for team {} in battle {}",
+        "Deposit: User {} staked {} lamports for team {} in battle {} ({} shares, {} lamport-seconds)",
         ctx.accounts.user.key(),
         amount,
         team,
-        battle.battle_id
+        battle.battle_id,
+        shares,
+        stake_seconds
     );
 
     Ok(())
@@ -258,9 +982,19 @@ pub struct Deposit<'info> {
     #[account(
         mut,
         seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
-        bump = battle.bump
+        bump = battle.bump,
+        constraint = !battle.paused @ MemeWarsError::BattlePaused
     )]
     pub battle: Account<'info, BattleState>,
+    
+    /// Protocol state (counters global)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        constraint = !protocol_state.paused @ MemeWarsError::ProtocolPaused
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
 
     #[account(
         init_if_needed,
@@ -313,139 +1047,1259 @@ pub struct Deposit<'info> {
 }
 
 // ============================================================================
-// SETTLE - Kết thúc battle và xác định winner
+// SETTLE - Optimistic settlement: propose -> dispute window -> finalize
+// ============================================================================
+
+/// Phase cho phép đề xuất kết quả
+pub const PROPOSE_PHASES: &[BattlePhase] = &[BattlePhase::AwaitingSettlement];
+
+/// Phase cho phép dispute và finalize
+pub const PROPOSED_PHASES: &[BattlePhase] = &[BattlePhase::Proposed];
+
+/// Đề xuất kết quả battle - lấy giá cuối cùng từ Pyth và xác định winner
+///
+/// Permissionless: bất kỳ ai cũng có thể đề xuất sau end_time.
+/// Giá lấy từ Pyth price update của đúng feed đã lưu trong battle lúc tạo (price feed
+/// của battle hoặc `PriceUpdateV2` post sau end_time), phải được publish trong
+/// ±SETTLEMENT_PRICE_TOLERANCE_SECONDS quanh end_time.
+/// Kết quả chỉ claim được sau `finalize_settlement` khi hết dispute window.
+pub fn propose_settlement(ctx: Context<ProposeSettlement>) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let clock = Clock::get()?;
+    
+    // Kiểm tra battle đã hết giờ và đang chờ settle
+    battle.sync_phase(clock.unix_timestamp)?;
+    battle.require_phase(PROPOSE_PHASES, clock.unix_timestamp)?;
+    
+    // Battle dùng KeeperSet chỉ settle qua attest_result
+    require!(
+        battle.keeper_set == Pubkey::default(),
+        MemeWarsError::KeeperAttestationRequired
+    );
+    
+    if cancel_if_low_participation(battle)? {
+        return Ok(());
+    }
+    
+    // Lấy giá cuối cùng từ Pyth Oracle và xác định winner
+    let review = record_final_prices(
+        battle,
+        &ctx.accounts.price_feed_a,
+        ctx.accounts.price_feed_b.as_ref(),
+    )?;
+    
+    battle.settled_by = ctx.accounts.proposer.key();
+    
+    // Circuit breaker: giá bất thường -> chờ admin xem xét thay vì mở dispute window
+    if review != constants::review_reason::NONE {
+        enter_review(battle, review, clock.unix_timestamp)?;
+        return Ok(());
+    }
+    
+    battle.dispute_deadline = clock
+        .unix_timestamp
+        .checked_add(constants::time::DISPUTE_WINDOW)
+        .ok_or(MemeWarsError::Overflow)?;
+    battle.transition(BattlePhase::Proposed)?;
+    
+    msg!(
+        "Battle {} settlement proposed by {}: Winner={} | Final prices: A={:?}, B={:?} | Dispute window until {}",
+        battle.battle_id,
+        battle.settled_by,
+        battle.winner,
+        battle.final_price_a,
+        battle.final_price_b,
+        battle.dispute_deadline
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ProposeSettlement<'info> {
+    /// Người đề xuất kết quả (bất kỳ ai), nhận keeper bounty khi finalize
+    pub proposer: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Pyth price update cho token A tại end_time: chính price feed của battle hoặc
+    /// một `PriceUpdateV2` được post sau đó cho cùng feed
+    /// CHECK: Owner, feed ID và publish_time được kiểm tra trong `pyth`
+    #[account(
+        constraint = pyth::is_price_update_for(&price_feed_a, &battle.feed_id_a)
+            @ MemeWarsError::InvalidPriceFeed
+    )]
+    pub price_feed_a: UncheckedAccount<'info>,
+    
+    /// Pyth price update cho token B tại end_time (không cần cho bracket battle)
+    /// CHECK: Owner, feed ID và publish_time được kiểm tra trong `pyth`
+    #[account(
+        constraint = pyth::is_price_update_for(price_feed_b, &battle.feed_id_b)
+            @ MemeWarsError::InvalidPriceFeed
+    )]
+    pub price_feed_b: Option<UncheckedAccount<'info>>,
+}
+
+/// Dispute kết quả đang đề xuất trong dispute window
+///
+/// Challenger đặt cọc DISPUTE_BOND và nộp Pyth price update tại end_time của các feed
+/// của battle (có thể post sau end_time, trong suốt dispute window).
+/// Nếu winner khác kết quả đang đề xuất thì kết quả mới thay thế, challenger trở
+/// thành người đề xuất và dispute window bắt đầu lại. Nếu kết quả cũ đến từ một
+/// challenger trước đó thì bond của họ bị chuyển cho challenger mới.
+pub fn dispute_settlement(ctx: Context<DisputeSettlement>) -> Result<()> {
+    let clock = Clock::get()?;
+    let battle = &mut ctx.accounts.battle;
+    
+    battle.require_phase(PROPOSED_PHASES, clock.unix_timestamp)?;
+    require!(
+        clock.unix_timestamp < battle.dispute_deadline,
+        MemeWarsError::DisputeWindowClosed
+    );
+    
+    // Kết quả do admin quyết định (review / force_resolve) không dispute bằng oracle được
+    require!(
+        battle.review_reason == constants::review_reason::NONE && !battle.force_resolved,
+        MemeWarsError::DisputeRejected
+    );
+    
+    // Đặt cọc bond vào battle PDA trước mọi thay đổi state, kể cả khi dispute
+    // đưa battle vào review, để không thể dispute miễn phí
+    let cpi_context = CpiContext::new(
+        ctx.accounts.system_program.to_account_info(),
+        anchor_lang::system_program::Transfer {
+            from: ctx.accounts.challenger.to_account_info(),
+            to: battle.to_account_info(),
+        },
+    );
+    anchor_lang::system_program::transfer(cpi_context, constants::fees::DISPUTE_BOND)?;
+    
+    let proposed_winner = battle.winner;
+    let previous_proposer = battle.settled_by;
+    let slashed_bond = battle.dispute_bond;
+    
+    // Bằng chứng mới từ oracle
+    let review = record_final_prices(
+        battle,
+        &ctx.accounts.price_feed_a,
+        ctx.accounts.price_feed_b.as_ref(),
+    )?;
+    
+    // Bằng chứng cho thấy oracle bất thường -> chờ admin xem xét
+    // Bond được giữ lại (cùng bond trước đó), người đề xuất cuối cùng nhận khi finalize
+    if review != constants::review_reason::NONE {
+        battle.settled_by = ctx.accounts.challenger.key();
+        battle.dispute_bond = slashed_bond
+            .checked_add(constants::fees::DISPUTE_BOND)
+            .ok_or(MemeWarsError::Overflow)?;
+        battle.dispute_count = battle.dispute_count.saturating_add(1);
+        enter_review(battle, review, clock.unix_timestamp)?;
+        return Ok(());
+    }
+    
+    require!(
+        battle.winner != proposed_winner,
+        MemeWarsError::DisputeRejected
+    );
+    
+    // Bond của challenger trước (kết quả sai) chuyển cho challenger mới
+    if slashed_bond > 0 {
+        **battle.to_account_info().try_borrow_mut_lamports()? -= slashed_bond;
+        **ctx.accounts.challenger.to_account_info().try_borrow_mut_lamports()? += slashed_bond;
+    }
+    
+    battle.settled_by = ctx.accounts.challenger.key();
+    battle.dispute_bond = constants::fees::DISPUTE_BOND;
+    battle.dispute_count = battle.dispute_count.saturating_add(1);
+    battle.dispute_deadline = clock
+        .unix_timestamp
+        .checked_add(constants::time::DISPUTE_WINDOW)
+        .ok_or(MemeWarsError::Overflow)?;
+    
+    msg!(
+        "Battle {} settlement disputed by {}: Winner {} -> {} | Previous proposer {} (slashed bond: {}) | Dispute window until {}",
+        battle.battle_id,
+        battle.settled_by,
+        proposed_winner,
+        battle.winner,
+        previous_proposer,
+        slashed_bond,
+        battle.dispute_deadline
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct DisputeSettlement<'info> {
+    /// Challenger đặt cọc bond
+    #[account(mut)]
+    pub challenger: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Pyth price update cho token A tại end_time: chính price feed của battle hoặc
+    /// một `PriceUpdateV2` được post sau đó cho cùng feed
+    /// CHECK: Owner, feed ID và publish_time được kiểm tra trong `pyth`
+    #[account(
+        constraint = pyth::is_price_update_for(&price_feed_a, &battle.feed_id_a)
+            @ MemeWarsError::InvalidPriceFeed
+    )]
+    pub price_feed_a: UncheckedAccount<'info>,
+    
+    /// Pyth price update cho token B tại end_time (không cần cho bracket battle)
+    /// CHECK: Owner, feed ID và publish_time được kiểm tra trong `pyth`
+    #[account(
+        constraint = pyth::is_price_update_for(price_feed_b, &battle.feed_id_b)
+            @ MemeWarsError::InvalidPriceFeed
+    )]
+    pub price_feed_b: Option<UncheckedAccount<'info>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Finalize kết quả sau dispute window - tính yield và cho phép claim
+///
+/// Permissionless. Người đề xuất cuối cùng nhận keeper bounty (trích từ protocol fee)
+/// và nhận lại bond nếu là challenger. Vault của các bracket từ 3 trở đi được truyền
+/// qua `remaining_accounts` (bắt buộc với bracket có stake) để ghi nhận yield.
+///
+/// Nếu tổng lamports của các vault thấp hơn principal, insurance fund bù trước (trong
+/// giới hạn coverage), phần còn thiếu thì mọi vault bị haircut cùng một tỷ lệ
+/// (`recovery_bps`) để người claim trước không rút cạn vault. Yield chỉ là phần lamports
+/// thực có vượt principal; lamports luôn được chia lại để mỗi vault đủ phần của phe mình.
+pub fn finalize_settlement<'info>(
+    ctx: Context<'_, '_, 'info, 'info, FinalizeSettlement<'info>>,
+) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let vault_a = &mut ctx.accounts.vault_a;
+    let vault_b = &mut ctx.accounts.vault_b;
+    let clock = Clock::get()?;
+    
+    battle.require_phase(PROPOSED_PHASES, clock.unix_timestamp)?;
+    require!(
+        clock.unix_timestamp >= battle.dispute_deadline,
+        MemeWarsError::DisputeWindowOpen
+    );
+    
+    // Vault được tạo khi có deposit đầu tiên; phe có stake thì phải truyền vault
+    require!(
+        vault_a.is_some() || battle.team_staked(team::TEAM_A) == 0,
+        MemeWarsError::InvalidVault
+    );
+    require!(
+        vault_b.is_some() || battle.team_staked(team::TEAM_B) == 0,
+        MemeWarsError::InvalidVault
+    );
+    
+    // Vault của các bracket còn lại, bracket có stake thì phải truyền vault
+    let mut bracket_vaults: Vec<Account<'info, Vault>> = Vec::new();
+    for vault_info in ctx.remaining_accounts.iter() {
+        let vault: Account<Vault> = Account::try_from(vault_info)?;
+        require!(
+            vault.battle_id == battle.battle_id
+                && vault.team > team::TEAM_B
+                && vault_info.key() == vault_address(battle.battle_id, vault.team, ctx.program_id)
+                && !bracket_vaults.iter().any(|seen| seen.team == vault.team),
+            MemeWarsError::InvalidVault
+        );
+        bracket_vaults.push(vault);
+    }
+    if battle.is_bracket() {
+        for bracket in (team::TEAM_B + 1)..=battle.bracket_count {
+            require!(
+                battle.team_staked(bracket) == 0
+                    || bracket_vaults.iter().any(|vault| vault.team == bracket),
+                MemeWarsError::InvalidVault
+            );
+        }
+    }
+    
+    battle.transition(BattlePhase::Settled)?;
+    
+    let rent = Rent::get()?;
+    let mut vaults: Vec<&mut Account<'info, Vault>> = vault_a
+        .iter_mut()
+        .chain(vault_b.iter_mut())
+        .chain(bracket_vaults.iter_mut())
+        .collect();
+    
+    // Lending trả về ít hơn principal -> insurance fund bù trước, còn thiếu thì
+    // haircut đều cho mọi người chơi. Yield chỉ là lamports thực có vượt principal
+    let (recovery_bps, loss, payout, realised_yield) = socialize_losses(
+        &mut vaults,
+        &ctx.accounts.protocol_state,
+        &ctx.accounts.insurance_fund.to_account_info(),
+        &rent,
+    )?;
+    battle.recovery_bps = recovery_bps;
+    battle.insurance_payout = payout;
+    if payout > 0 {
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        protocol_state.insurance_balance = protocol_state.insurance_balance.saturating_sub(payout);
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        insurance_fund.total_paid_out = insurance_fund
+            .total_paid_out
+            .checked_add(payout)
+            .ok_or(MemeWarsError::Overflow)?;
+    }
+    if loss > 0 {
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        protocol_state.total_tvl = protocol_state.total_tvl.saturating_sub(loss);
+    }
+    
+    // Protocol fee tính trên yield thực nhận, theo tỷ lệ snapshot khi tạo battle
+    let (winner_yield, protocol_fee) = battle.split_yield(realised_yield);
+    battle.total_yield_collected = realised_yield;
+    battle.winner_yield = winner_yield;
+    
+    // Update vault yield info
+    // Winner nhận toàn bộ, tie chia theo tỷ lệ stake
+    for vault in vaults.iter_mut() {
+        vault.yield_collected = battle.team_yield(vault.team);
+    }
+    
+    // Mỗi vault phải giữ đủ principal (sau haircut) + yield của phe mình,
+    // phần dư còn lại (protocol fee) nằm lại trong vault để sweep
+    rebalance_vaults(&vaults, &rent)?;
+    
+    // Keeper bounty trích từ protocol fee: chỉ trả từ lamports vượt quá số tiền nợ
+    // người chơi (principal + yield đã chia), có thể gom từ nhiều vault
+    let proposer_info = ctx.accounts.proposer.to_account_info();
+    let bounty = keeper_bounty(realised_yield, protocol_fee)?;
+    let mut paid_bounty: u64 = 0;
+    for vault in vaults.iter() {
+        if paid_bounty >= bounty {
+            break;
+        }
+        let vault_info = vault.to_account_info();
+        let excess = vault.excess_lamports(
+            vault_info.lamports(),
+            rent.minimum_balance(vault_info.data_len()),
+        );
+        let amount = excess.min(bounty - paid_bounty);
+        if amount > 0 {
+            **vault_info.try_borrow_mut_lamports()? -= amount;
+            **proposer_info.try_borrow_mut_lamports()? += amount;
+            paid_bounty += amount;
+        }
+    }
+    
+    // Trả lại bond cho challenger có kết quả được giữ nguyên
+    let returned_bond = battle.dispute_bond;
+    if returned_bond > 0 {
+        **battle.to_account_info().try_borrow_mut_lamports()? -= returned_bond;
+        **proposer_info.try_borrow_mut_lamports()? += returned_bond;
+        battle.dispute_bond = 0;
+    }
+    
+    battle.protocol_fee_collected = protocol_fee.saturating_sub(paid_bounty);
+    battle.keeper_bounty = paid_bounty;
+    
+    // Chuyển protocol fee từ lamports dư của vault vào treasury
+    // Phần chưa đủ (yield chưa về) thu sau qua collect_protocol_fee
+    let treasury_info = ctx.accounts.treasury.to_account_info();
+    let mut fees_swept: u64 = 0;
+    for vault in vaults.iter() {
+        let swept = sweep_protocol_fee(battle, vault, &vault.to_account_info(), &treasury_info, &rent)?;
+        fees_swept = fees_swept.saturating_add(swept);
+    }
+    
+    // Vault của các bracket còn lại không được Anchor tự ghi lại
+    for vault in bracket_vaults.iter() {
+        vault.exit(ctx.program_id)?;
+    }
+    
+    record_collected_fees(
+        &mut ctx.accounts.protocol_state,
+        &mut ctx.accounts.treasury,
+        &mut ctx.accounts.insurance_fund,
+        fees_swept,
+    )?;
+    
+    let yield_a = battle.team_yield(team::TEAM_A);
+    let yield_b = battle.team_yield(team::TEAM_B);
+    
+    // Growth để log
+    let growth_a = battle
+        .final_price_a
+        .map(|final_a| BattleState::calculate_growth_bps(battle.initial_price_a, final_a))
+        .unwrap_or(0);
+    let growth_b = battle
+        .final_price_b
+        .map(|final_b| BattleState::calculate_growth_bps(battle.initial_price_b, final_b))
+        .unwrap_or(0);
+    
+    if battle.is_bracket() {
+        msg!(
+            "Bracket battle {} settled (proposed by {}, {} disputes): Growth={} bps | Winner bracket={} | Winner yield={} | Keeper bounty={}",
+            battle.battle_id,
+            battle.settled_by,
+            battle.dispute_count,
+            growth_a,
+            battle.winner,
+            winner_yield,
+            paid_bounty
+        );
+    } else {
+        msg!(
+            "Battle {} settled (proposed by {}, {} disputes): Winner={} | Growth A={} bps, B={} bps (handicap {} bps) | Yield A={}, B={} | Keeper bounty={}",
+            battle.battle_id,
+            battle.settled_by,
+            battle.dispute_count,
+            battle.winner,
+            growth_a,
+            growth_b,
+            battle.handicap_bps,
+            yield_a,
+            yield_b,
+            paid_bounty
+        );
+    }
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct FinalizeSettlement<'info> {
+    /// Người gọi finalize (bất kỳ ai)
+    pub finalizer: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Protocol state (counters global)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    /// Treasury nhận protocol fee
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_TREASURY],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, ProtocolTreasury>,
+    
+    /// Insurance fund nhận một phần protocol fee và bù thiếu hụt principal
+    #[account(
+        mut,
+        seeds = [constants::seeds::INSURANCE_FUND],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    
+    /// Người đề xuất kết quả cuối cùng, nhận bounty và bond
+    /// CHECK: Chỉ nhận lamports, phải khớp với `battle.settled_by`
+    #[account(mut, address = battle.settled_by @ MemeWarsError::Unauthorized)]
+    pub proposer: UncheckedAccount<'info>,
+    
+    /// Vault của Team A, None nếu chưa ai deposit vào phe này
+    #[account(
+        mut,
+        seeds = [constants::seeds::VAULT, battle.battle_id.to_le_bytes().as_ref(), team::TEAM_A.to_le_bytes().as_ref()],
+        bump = vault_a.bump
+    )]
+    pub vault_a: Option<Account<'info, Vault>>,
+    
+    /// Vault của Team B, None nếu chưa ai deposit vào phe này
+    #[account(
+        mut,
+        seeds = [constants::seeds::VAULT, battle.battle_id.to_le_bytes().as_ref(), team::TEAM_B.to_le_bytes().as_ref()],
+        bump = vault_b.bump
+    )]
+    pub vault_b: Option<Account<'info, Vault>>,
+}
+
+/// Phát hiện thiếu hụt khi settle: tổng lamports (trên rent) của các vault < principal
+/// 
+/// Khi thiếu, insurance fund bù vào các vault thiếu trước (giới hạn bởi coverage của
+/// battle và số dư quỹ). Nếu vẫn thiếu, ghi giảm `total_amount` của mọi vault cùng một
+/// tỷ lệ, nên `shares_to_amount` của mọi người chơi chịu cùng haircut. Lamports được
+/// chia lại giữa các vault sau đó bởi `rebalance_vaults`.
+/// Trả về (recovery_bps, tổng lamports bị ghi giảm, lamports insurance đã bù,
+/// yield thực nhận = phần lamports vượt principal, 0 nếu có thiếu hụt).
+fn socialize_losses(
+    vaults: &mut [&mut Account<Vault>],
+    protocol_state: &ProtocolState,
+    insurance_info: &AccountInfo,
+    rent: &Rent,
+) -> Result<(u64, u64, u64, u64)> {
+    let mut available: Vec<u64> = vaults
+        .iter()
+        .map(|vault| {
+            let info = vault.to_account_info();
+            info.lamports().saturating_sub(rent.minimum_balance(info.data_len()))
+        })
+        .collect();
+    let principal: Vec<u64> = vaults
+        .iter()
+        .map(|vault| vault.total_amount.saturating_sub(vault.claimed_amount))
+        .collect();
+    let total_principal = principal.iter().fold(0u64, |sum, p| sum.saturating_add(*p));
+    
+    // Insurance fund bù thiếu hụt trước khi haircut
+    let shortfall = total_principal
+        .saturating_sub(available.iter().fold(0u64, |sum, a| sum.saturating_add(*a)));
+    let fund_available = insurance_info
+        .lamports()
+        .saturating_sub(rent.minimum_balance(insurance_info.data_len()));
+    let payout = protocol_state
+        .insurance_payout(total_principal, shortfall)
+        .min(fund_available);
+    let mut remaining = payout;
+    for (index, vault) in vaults.iter().enumerate() {
+        let top_up = principal[index].saturating_sub(available[index]).min(remaining);
+        if top_up > 0 {
+            **insurance_info.try_borrow_mut_lamports()? -= top_up;
+            **vault.to_account_info().try_borrow_mut_lamports()? += top_up;
+            available[index] += top_up;
+            remaining -= top_up;
+        }
+    }
+    if payout > 0 {
+        msg!(
+            "Insurance fund covered {} of {} lamports shortfall",
+            payout,
+            shortfall
+        );
+    }
+    
+    let total_available = available.iter().fold(0u64, |sum, a| sum.saturating_add(*a));
+    let ratio = recovery_bps(total_available, total_principal);
+    
+    if ratio >= fees::BPS_DIVISOR {
+        let realised_yield = if payout > 0 {
+            0
+        } else {
+            total_available.saturating_sub(total_principal)
+        };
+        return Ok((ratio, 0, payout, realised_yield));
+    }
+    
+    let mut loss: u64 = 0;
+    for (vault, principal) in vaults.iter_mut().zip(principal) {
+        let target = apply_haircut(principal, ratio);
+        loss = loss.saturating_add(principal.saturating_sub(target));
+        vault.total_amount = vault.claimed_amount.saturating_add(target);
+    }
+    
+    msg!(
+        "Settlement shortfall: recovered {} bps of {} lamports principal, loss {}",
+        ratio,
+        total_principal,
+        loss
+    );
+    
+    Ok((ratio, loss, payout, 0))
+}
+
+/// Chuyển lamports giữa các vault để mỗi vault giữ đủ số tiền còn nợ người chơi
+/// (principal sau haircut + yield của phe)
+/// 
+/// Luôn chạy khi settle: kể cả khi tổng không thiếu, lãi/lỗ của lending có thể
+/// phân bổ lệch giữa các vault.
+fn rebalance_vaults(vaults: &[&mut Account<Vault>], rent: &Rent) -> Result<()> {
+    let available: Vec<u64> = vaults
+        .iter()
+        .map(|vault| {
+            let info = vault.to_account_info();
+            info.lamports().saturating_sub(rent.minimum_balance(info.data_len()))
+        })
+        .collect();
+    let targets: Vec<u64> = vaults.iter().map(|vault| vault.claimable_amount()).collect();
+    
+    for (from, to, amount) in rebalance_transfers(&available, &targets) {
+        **vaults[from].to_account_info().try_borrow_mut_lamports()? -= amount;
+        **vaults[to].to_account_info().try_borrow_mut_lamports()? += amount;
+    }
+    Ok(())
+}
+
+/// Chuyển phần protocol fee còn nợ của battle từ vault sang treasury
+/// Chỉ dùng lamports vượt quá số tiền còn nợ người chơi (principal + yield đã chia)
+fn sweep_protocol_fee(
+    battle: &mut BattleState,
+    vault: &Vault,
+    vault_info: &AccountInfo,
+    treasury_info: &AccountInfo,
+    rent: &Rent,
+) -> Result<u64> {
+    let owed = battle.protocol_fee_collected.saturating_sub(battle.protocol_fee_paid);
+    let excess = vault.excess_lamports(
+        vault_info.lamports(),
+        rent.minimum_balance(vault_info.data_len()),
+    );
+    let amount = owed.min(excess);
+    
+    if amount > 0 {
+        **vault_info.try_borrow_mut_lamports()? -= amount;
+        **treasury_info.try_borrow_mut_lamports()? += amount;
+        battle.protocol_fee_paid = battle
+            .protocol_fee_paid
+            .checked_add(amount)
+            .ok_or(MemeWarsError::Overflow)?;
+    }
+    
+    Ok(amount)
+}
+
+/// Ghi nhận fees đã chuyển vào treasury và chuyển phần của insurance fund sang quỹ
+fn record_collected_fees(
+    protocol_state: &mut ProtocolState,
+    treasury: &mut Account<ProtocolTreasury>,
+    insurance_fund: &mut Account<InsuranceFund>,
+    amount: u64,
+) -> Result<()> {
+    let (treasury_share, insurance_share) = protocol_state.split_fee(amount);
+    if insurance_share > 0 {
+        **treasury.to_account_info().try_borrow_mut_lamports()? -= insurance_share;
+        **insurance_fund.to_account_info().try_borrow_mut_lamports()? += insurance_share;
+    }
+    
+    protocol_state.total_fees_collected = protocol_state
+        .total_fees_collected
+        .checked_add(amount)
+        .ok_or(MemeWarsError::Overflow)?;
+    protocol_state.insurance_balance = protocol_state
+        .insurance_balance
+        .checked_add(insurance_share)
+        .ok_or(MemeWarsError::Overflow)?;
+    treasury.total_collected = treasury
+        .total_collected
+        .checked_add(treasury_share)
+        .ok_or(MemeWarsError::Overflow)?;
+    insurance_fund.total_deposited = insurance_fund
+        .total_deposited
+        .checked_add(insurance_share)
+        .ok_or(MemeWarsError::Overflow)?;
+    Ok(())
+}
+
+// ============================================================================
+// COLLECT PROTOCOL FEE - Thu phần fee còn thiếu sau khi settle
+// ============================================================================
+
+/// Phase cho phép thu fee
+pub const COLLECT_FEE_PHASES: &[BattlePhase] = &[BattlePhase::Settled];
+
+/// Chuyển phần protocol fee chưa thu (khi finalize vault chưa đủ lamports dư) vào treasury
+/// Bất kỳ ai cũng có thể gọi, chỉ lấy lamports không nợ người chơi
+pub fn collect_protocol_fee(ctx: Context<CollectProtocolFee>) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let vault = &ctx.accounts.vault;
+    let clock = Clock::get()?;
+    
+    battle.require_phase(COLLECT_FEE_PHASES, clock.unix_timestamp)?;
+    
+    let rent = Rent::get()?;
+    let amount = sweep_protocol_fee(
+        battle,
+        vault,
+        &vault.to_account_info(),
+        &ctx.accounts.treasury.to_account_info(),
+        &rent,
+    )?;
+    record_collected_fees(
+        &mut ctx.accounts.protocol_state,
+        &mut ctx.accounts.treasury,
+        &mut ctx.accounts.insurance_fund,
+        amount,
+    )?;
+    
+    msg!(
+        "Battle {} protocol fee collected from vault {}: {} lamports | Paid {}/{}",
+        battle.battle_id,
+        vault.team,
+        amount,
+        battle.protocol_fee_paid,
+        battle.protocol_fee_collected
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFee<'info> {
+    /// Người gọi (bất kỳ ai)
+    pub collector: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::VAULT, battle.battle_id.to_le_bytes().as_ref(), vault.team.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, Vault>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_TREASURY],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, ProtocolTreasury>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::INSURANCE_FUND],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+}
+
+// ============================================================================
+// WITHDRAW TREASURY - Treasury manager rút fees
+// ============================================================================
+
+/// Rút lamports từ treasury (không chạm vào rent-exempt minimum)
+pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+    let treasury = &mut ctx.accounts.treasury;
+    let treasury_info = treasury.to_account_info();
+    
+    let rent = Rent::get()?;
+    let available = treasury_info
+        .lamports()
+        .saturating_sub(rent.minimum_balance(treasury_info.data_len()));
+    require!(amount <= available, MemeWarsError::InsufficientFunds);
+    
+    **treasury_info.try_borrow_mut_lamports()? -= amount;
+    **ctx.accounts.recipient.to_account_info().try_borrow_mut_lamports()? += amount;
+    
+    treasury.total_withdrawn = treasury
+        .total_withdrawn
+        .checked_add(amount)
+        .ok_or(MemeWarsError::Overflow)?;
+    
+    msg!(
+        "Treasury withdrawal: {} lamports to {} | Collected {}, withdrawn {}",
+        amount,
+        ctx.accounts.recipient.key(),
+        treasury.total_collected,
+        treasury.total_withdrawn
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTreasury<'info> {
+    pub treasury_manager: Signer<'info>,
+    
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = treasury_manager @ MemeWarsError::Unauthorized,
+        has_one = treasury @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_TREASURY],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, ProtocolTreasury>,
+    
+    /// Địa chỉ nhận fees
+    /// CHECK: Chỉ nhận lamports
+    #[account(mut)]
+    pub recipient: UncheckedAccount<'info>,
+}
+
+// ============================================================================
+// FUND INSURANCE - Nạp thêm vốn cho insurance fund
+// ============================================================================
+
+/// Chuyển lamports vào insurance fund và ghi nhận vào số dư bảo hiểm
+/// 
+/// Lamports gửi thẳng vào PDA không được tính vào `insurance_balance`, phải nạp qua đây
+pub fn fund_insurance(ctx: Context<FundInsurance>, amount: u64) -> Result<()> {
+    require!(amount > 0, MemeWarsError::InsufficientFunds);
+    
+    let cpi_context = CpiContext::new(
+        ctx.accounts.system_program.to_account_info(),
+        anchor_lang::system_program::Transfer {
+            from: ctx.accounts.funder.to_account_info(),
+            to: ctx.accounts.insurance_fund.to_account_info(),
+        },
+    );
+    anchor_lang::system_program::transfer(cpi_context, amount)?;
+    
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    protocol_state.insurance_balance = protocol_state
+        .insurance_balance
+        .checked_add(amount)
+        .ok_or(MemeWarsError::Overflow)?;
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
+    insurance_fund.total_deposited = insurance_fund
+        .total_deposited
+        .checked_add(amount)
+        .ok_or(MemeWarsError::Overflow)?;
+    
+    msg!(
+        "Insurance funded: {} lamports by {} | Balance {}",
+        amount,
+        ctx.accounts.funder.key(),
+        protocol_state.insurance_balance
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct FundInsurance<'info> {
+    #[account(mut)]
+    pub funder: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = insurance_fund @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::INSURANCE_FUND],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Đọc giá cuối cùng từ các Pyth feed của battle và xác định winner
+/// Bracket battle chỉ cần price feed A
+/// 
+/// Trả về lý do circuit breaker (`review_reason::NONE` nếu giá hợp lý)
+fn record_final_prices(
+    battle: &mut BattleState,
+    price_feed_a: &AccountInfo,
+    price_feed_b: Option<&UncheckedAccount>,
+) -> Result<u8> {
+    // Chỉ chấp nhận giá publish quanh end_time, không phải giá tại lúc gọi
+    let final_a = pyth::get_settlement_price(price_feed_a, &battle.feed_id_a, battle.end_time)?;
+    battle.final_price_a = Some(final_a.price);
+    battle.final_conf_a = final_a.conf;
+    let mut ema_b = None;
+    
+    if !battle.is_bracket() {
+        let price_feed_b = price_feed_b.ok_or(MemeWarsError::InvalidPriceFeed)?;
+        let final_b = pyth::get_settlement_price(price_feed_b, &battle.feed_id_b, battle.end_time)?;
+        battle.final_price_b = Some(final_b.price);
+        battle.final_conf_b = final_b.conf;
+        ema_b = final_b.ema;
+    }
+    
+    // Xác định winner (handicap, draw band, confidence ties, bracket)
+    battle.winner = battle.determine_winner();
+    Ok(battle.circuit_breaker_reason(final_a.ema, ema_b))
+}
+
+/// Battle một phía hoặc dưới ngưỡng tham gia -> tự hủy, hoàn principal + yield
+/// (không cần oracle nên cả khi price feed lỗi vẫn hoàn tiền được)
+fn cancel_if_low_participation(battle: &mut BattleState) -> Result<bool> {
+    if battle.has_min_participation() {
+        return Ok(false);
+    }
+    
+    battle.cancel(constants::cancel_reason::LOW_PARTICIPATION)?;
+    msg!(
+        "Battle {} cancelled at settlement: not enough participation (min {} lamports per side) | Stake A={}, B={}, total={}",
+        battle.battle_id,
+        battle.min_team_stake,
+        battle.team_staked(team::TEAM_A),
+        battle.team_staked(team::TEAM_B),
+        battle.total_staked()
+    );
+    Ok(true)
+}
+
+/// Chuyển battle sang NeedsReview khi circuit breaker bị kích hoạt
+fn enter_review(battle: &mut BattleState, reason: u8, now: i64) -> Result<()> {
+    battle.review_reason = reason;
+    battle.review_deadline = now
+        .checked_add(constants::time::REVIEW_PERIOD)
+        .ok_or(MemeWarsError::Overflow)?;
+    battle.transition(BattlePhase::NeedsReview)?;
+    
+    msg!(
+        "Battle {} needs review: circuit breaker reason {} | Final prices: A={:?}, B={:?} | Limits: growth {} bps, EMA divergence {} bps | Review deadline {}",
+        battle.battle_id,
+        reason,
+        battle.final_price_a,
+        battle.final_price_b,
+        battle.max_growth_bps,
+        battle.max_ema_divergence_bps,
+        battle.review_deadline
+    );
+    
+    Ok(())
+}
+
+// ============================================================================
+// RESOLVE REVIEW - Admin quyết định kết quả khi circuit breaker bị kích hoạt
+// ============================================================================
+
+/// Phase cho phép admin xử lý review
+pub const REVIEW_PHASES: &[BattlePhase] = &[BattlePhase::NeedsReview];
+
+/// Admin (authority của battle hoặc settler của protocol, nên là multisig) quyết định winner
+/// Kết quả đi qua timelock DISPUTE_WINDOW rồi mới finalize được
+pub fn resolve_review(ctx: Context<ResolveReview>, winner: u8) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let clock = Clock::get()?;
+    
+    battle.require_phase(REVIEW_PHASES, clock.unix_timestamp)?;
+    require!(
+        clock.unix_timestamp < battle.review_deadline,
+        MemeWarsError::ReviewExpired
+    );
+    require!(
+        winner == team::NONE || battle.is_valid_team(winner),
+        MemeWarsError::InvalidTeam
+    );
+    
+    battle.winner = winner;
+    battle.dispute_deadline = clock
+        .unix_timestamp
+        .checked_add(constants::time::DISPUTE_WINDOW)
+        .ok_or(MemeWarsError::Overflow)?;
+    battle.transition(BattlePhase::Proposed)?;
+    
+    msg!(
+        "Battle {} review resolved by {}: Winner={} (review reason {}) | Finalizable after {}",
+        battle.battle_id,
+        ctx.accounts.authority.key(),
+        winner,
+        battle.review_reason,
+        battle.dispute_deadline
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ResolveReview<'info> {
+    /// Authority của battle hoặc settler của protocol
+    pub authority: Signer<'info>,
+    
+    /// Protocol state (roles)
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump,
+        constraint = protocol_state.can_settle(&battle, &authority.key()) @ MemeWarsError::Unauthorized
+    )]
+    pub battle: Account<'info, BattleState>,
+}
+
+// ============================================================================
+// FORCE RESOLVE - Admin quyết định kết quả khi oracle hỏng vĩnh viễn
+// ============================================================================
+
+/// Phase cho phép admin force_resolve
+pub const FORCE_RESOLVE_PHASES: &[BattlePhase] = &[BattlePhase::AwaitingSettlement];
+
+/// Admin (authority của battle hoặc settler của protocol) tuyên bố winner hoặc hòa khi không settle được bằng oracle
+/// 
+/// Chỉ trong FORCE_RESOLVE_WINDOW sau `end_time + SETTLEMENT_GRACE_PERIOD`, trước khi
+/// cancel SETTLEMENT_TIMEOUT mở cho mọi người. Kết quả được công khai và đi qua
+/// timelock FORCE_RESOLVE_TIMELOCK; trong thời gian đó người không đồng ý có thể
+/// `withdraw` lại toàn bộ tiền không mất penalty.
+pub fn force_resolve(ctx: Context<ForceResolve>, winner: u8) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let clock = Clock::get()?;
+    
+    battle.sync_phase(clock.unix_timestamp)?;
+    battle.require_phase(FORCE_RESOLVE_PHASES, clock.unix_timestamp)?;
+    require!(
+        battle.can_force_resolve(clock.unix_timestamp),
+        MemeWarsError::ForceResolveWindowExpired
+    );
+    
+    // Winner phải là phe có stake (giống settle bracket: phe trống không thể thắng)
+    require!(
+        winner == team::NONE
+            || (battle.is_valid_team(winner) && battle.team_staked(winner) > 0),
+        MemeWarsError::InvalidTeam
+    );
+    
+    if cancel_if_low_participation(battle)? {
+        return Ok(());
+    }
+    
+    battle.winner = winner;
+    battle.force_resolved = true;
+    battle.settled_by = ctx.accounts.authority.key();
+    battle.dispute_deadline = clock
+        .unix_timestamp
+        .checked_add(constants::time::FORCE_RESOLVE_TIMELOCK)
+        .ok_or(MemeWarsError::Overflow)?;
+    battle.transition(BattlePhase::Proposed)?;
+    
+    msg!(
+        "Battle {} force-resolved by {}: Winner={} | Penalty-free withdrawals until {}, finalizable after",
+        battle.battle_id,
+        battle.settled_by,
+        winner,
+        battle.dispute_deadline
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ForceResolve<'info> {
+    /// Authority của battle hoặc settler của protocol
+    pub authority: Signer<'info>,
+    
+    /// Protocol state (roles)
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump,
+        constraint = protocol_state.can_settle(&battle, &authority.key()) @ MemeWarsError::Unauthorized
+    )]
+    pub battle: Account<'info, BattleState>,
+}
+
+// ============================================================================
+// KEEPER SET - Đăng ký keeper attest kết quả (M-of-N)
+// ============================================================================
+
+/// Tạo KeeperSet của authority với N keeper và threshold M
+pub fn create_keeper_set(
+    ctx: Context<CreateKeeperSet>,
+    keepers: Vec<Pubkey>,
+    threshold: u8,
+) -> Result<()> {
+    let keeper_set = &mut ctx.accounts.keeper_set;
+    
+    keeper_set.authority = ctx.accounts.authority.key();
+    keeper_set.set_keepers(&keepers, threshold)?;
+    keeper_set.bump = ctx.bumps.keeper_set;
+    
+    msg!(
+        "Keeper set {} created by {}: {}-of-{} keepers",
+        keeper_set.key(),
+        keeper_set.authority,
+        threshold,
+        keepers.len()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CreateKeeperSet<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = authority,
+        space = KeeperSet::LEN,
+        seeds = [constants::seeds::KEEPER_SET, authority.key().as_ref()],
+        bump
+    )]
+    pub keeper_set: Account<'info, KeeperSet>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Thay danh sách keeper và threshold
+/// Attestation của keeper bị gỡ không còn được tính
+pub fn update_keeper_set(
+    ctx: Context<UpdateKeeperSet>,
+    keepers: Vec<Pubkey>,
+    threshold: u8,
+) -> Result<()> {
+    let keeper_set = &mut ctx.accounts.keeper_set;
+    
+    keeper_set.set_keepers(&keepers, threshold)?;
+    
+    msg!(
+        "Keeper set {} updated: {}-of-{} keepers",
+        keeper_set.key(),
+        threshold,
+        keepers.len()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateKeeperSet<'info> {
+    pub authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::KEEPER_SET, authority.key().as_ref()],
+        bump = keeper_set.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub keeper_set: Account<'info, KeeperSet>,
+}
+
+// ============================================================================
+// ATTEST RESULT - Keeper nộp kết quả, đủ M attestation trùng khớp thì settle
 // ============================================================================
 
-/// Settle battle - lấy giá cuối cùng từ Pyth và xác định winner
-pub fn settle(ctx: Context<SettleBattle>) -> Result<()> {
+/// Keeper attest giá trị cuối của battle dùng KeeperSet
+/// 
+/// Khi M keeper attest cùng giá trị, kết quả được ghi nhận và battle chuyển sang
+/// Proposed với dispute deadline = now (finalize được ngay, không qua dispute).
+/// Keeper nộp attestation cuối cùng nhận keeper bounty khi finalize.
+pub fn attest_result(
+    ctx: Context<AttestResult>,
+    final_value_a: i64,
+    final_value_b: i64,
+) -> Result<()> {
     let battle = &mut ctx.accounts.battle;
-    let vault_a = &mut ctx.accounts.vault_a;
-    let vault_b = &mut ctx.accounts.vault_b;
+    let keeper_set = &ctx.accounts.keeper_set;
+    let attestations = &mut ctx.accounts.attestations;
+    let keeper = ctx.accounts.keeper.key();
     let clock = Clock::get()?;
     
-    // Kiểm tra battle đang active
-    require!(battle.is_active(), MemeWarsError::BattleNotActive);
+    battle.sync_phase(clock.unix_timestamp)?;
+    battle.require_phase(PROPOSE_PHASES, clock.unix_timestamp)?;
+    require!(keeper_set.is_keeper(&keeper), MemeWarsError::NotAKeeper);
     
-    // Kiểm tra đã đến thời gian kết thúc
+    if cancel_if_low_participation(battle)? {
+        return Ok(());
+    }
+    
+    // Bracket battle chỉ có một giá trị
+    let final_value_b = if battle.is_bracket() { 0 } else { final_value_b };
     require!(
-        battle.is_ended(clock.unix_timestamp),
-        MemeWarsError::BattleNotEnded
+        final_value_a > 0 && (battle.is_bracket() || final_value_b > 0),
+        MemeWarsError::InvalidAttestation
     );
     
-    // Lấy giá cuối cùng từ Pyth Oracle
-    let final_price_a = pyth::get_pyth_price(&ctx.accounts.price_feed_a)?;
-    let final_price_b = pyth::get_pyth_price(&ctx.accounts.price_feed_b)?;
-    
-    // Cập nhật final prices
-    battle.final_price_a = Some(final_price_a);
-    battle.final_price_b = Some(final_price_b);
-    
-    // Tính % growth
-    let growth_a = BattleState::calculate_growth_bps(battle.initial_price_a, final_price_a);
-    let growth_b = BattleState::calculate_growth_bps(battle.initial_price_b, final_price_b);
-    
-    // Xác định winner
-    let winner = if growth_a > growth_b {
-        team::TEAM_A
-    } else if growth_b > growth_a {
-        team::TEAM_B
-    } else {
-        team::NONE // Tie
-    };
-    
-    battle.winner = winner;
-    battle.status = battle_status::SETTLED;
-    
-    // Tính yield từ lending
-    // Trong implementation thực tế, sẽ rút từ Marinade và tính yield
-    // Ở đây giả định yield = 0.1% của total staked (cho demo)
-    let total_staked = battle.total_staked_a.saturating_add(battle.total_staked_b);
-    let simulated_yield = total_staked / 1000; // 0.1% yield for demo
-    
-    // Tính protocol fee (5%)
-    let protocol_fee = simulated_yield
-        .checked_mul(fees::PROTOCOL_FEE_BPS)
-        .ok_or(MemeWarsError::Overflow)?
-        .checked_div(fees::BPS_DIVISOR)
-        .ok_or(MemeWarsError::Overflow)?;
-    
-    let winner_yield = simulated_yield.saturating_sub(protocol_fee);
-    
-    battle.total_yield_collected = simulated_yield;
-    battle.winner_yield = winner_yield;
-    battle.protocol_fee_collected = protocol_fee;
+    attestations.battle_id = battle.battle_id;
+    attestations.bump = ctx.bumps.attestations;
+    attestations.record(keeper_set, KeeperAttestation {
+        keeper,
+        final_value_a,
+        final_value_b,
+    })?;
     
-    // Update vault yield info
-    let yield_a = if winner == team::TEAM_A {
-        winner_yield
-    } else if winner == team::NONE {
-        // Tie: split proportionally
-        winner_yield.checked_mul(battle.total_staked_a)
-            .ok_or(MemeWarsError::Overflow)?
-            .checked_div(total_staked.max(1))
-            .ok_or(MemeWarsError::Overflow)?
-    } else {
-        0
-    };
+    let matching = attestations.matching_count(keeper_set, final_value_a, final_value_b);
+    msg!(
+        "Battle {} attested by {}: A={}, B={} | {}/{} matching attestations",
+        battle.battle_id,
+        keeper,
+        final_value_a,
+        final_value_b,
+        matching,
+        keeper_set.threshold
+    );
     
-    let yield_b = winner_yield.saturating_sub(yield_a);
+    if matching < keeper_set.threshold {
+        return Ok(());
+    }
     
-    vault_a.yield_collected = yield_a;
-    vault_b.yield_collected = yield_b;
+    battle.final_price_a = Some(final_value_a);
+    battle.final_price_b = if battle.is_bracket() { None } else { Some(final_value_b) };
+    battle.final_conf_a = 0;
+    battle.final_conf_b = 0;
+    battle.winner = battle.determine_winner();
+    battle.settled_by = keeper;
+    battle.dispute_deadline = clock.unix_timestamp;
+    battle.transition(BattlePhase::Proposed)?;
     
     msg!(
-        "Battle {} settled: Winner={} | Growth A={} bps, B={} bps | Yield A={}, B={}",
+        "Battle {} resolved by keeper quorum ({}-of-{}): Winner={}",
         battle.battle_id,
-        winner,
-        growth_a,
-        growth_b,
-        yield_a,
-        yield_b
+        keeper_set.threshold,
+        keeper_set.keeper_count,
+        battle.winner
     );
     
     Ok(())
 }
 
 #[derive(Accounts)]
-pub struct SettleBattle<'info> {
+pub struct AttestResult<'info> {
+    /// Keeper đã đăng ký trong KeeperSet của battle, trả rent cho attestation account
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub keeper: Signer<'info>,
     
     #[account(
         mut,
         seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
-        bump = battle.bump,
-        constraint = battle.authority == authority.key() @ MemeWarsError::Unauthorized
+        bump = battle.bump
     )]
     pub battle: Account<'info, BattleState>,
     
     #[account(
-        mut,
-        seeds = [constants::seeds::VAULT, battle.battle_id.to_le_bytes().as_ref(), team::TEAM_A.to_le_bytes().as_ref()],
-        bump = vault_a.bump
+        address = battle.keeper_set @ MemeWarsError::KeeperAttestationRequired
     )]
-    pub vault_a: Account<'info, Vault>,
+    pub keeper_set: Account<'info, KeeperSet>,
     
     #[account(
-        mut,
-        seeds = [constants::seeds::VAULT, battle.battle_id.to_le_bytes().as_ref(), team::TEAM_B.to_le_bytes().as_ref()],
-        bump = vault_b.bump
+        init_if_needed,
+        payer = keeper,
+        space = SettlementAttestations::LEN,
+        seeds = [constants::seeds::ATTESTATIONS, battle.battle_id.to_le_bytes().as_ref()],
+        bump
     )]
-    pub vault_b: Account<'info, Vault>,
-    
-    /// Pyth price feed for token A
-    /// CHECK: Pyth price feed account
-    pub price_feed_a: UncheckedAccount<'info>,
+    pub attestations: Account<'info, SettlementAttestations>,
     
-    /// Pyth price feed for token B
-    /// CHECK: Pyth price feed account
-    pub price_feed_b: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 // ============================================================================
 // CLAIM REWARD - Người chơi nhận thưởng sau khi battle kết thúc
 // ============================================================================
 
+/// Phase cho phép claim
+pub const CLAIM_PHASES: &[BattlePhase] = &[BattlePhase::Settled];
+
 /// Claim reward sau khi battle đã settle
 /// - Winner: nhận principal + yield
 /// - Loser: nhận principal only
@@ -453,9 +2307,10 @@ pub fn claim_reward(ctx: Context<ClaimReward>) -> Result<()> {
     let battle = &ctx.accounts.battle;
     let user_state = &mut ctx.accounts.user_state;
     let vault = &mut ctx.accounts.vault;
+    let clock = Clock::get()?;
     
-    // Kiểm tra battle đã settle
-    require!(battle.is_settled(), MemeWarsError::BattleNotSettled);
+    // Kiểm tra battle đã settle (đề xuất đã finalize sau dispute window)
+    battle.require_phase(CLAIM_PHASES, clock.unix_timestamp)?;
     
     // Kiểm tra chưa claim
     require!(!user_state.claimed, MemeWarsError::AlreadyClaimed);
@@ -464,7 +2319,8 @@ pub fn claim_reward(ctx: Context<ClaimReward>) -> Result<()> {
     require!(user_state.team == vault.team, MemeWarsError::InvalidVault);
     
     // Tính số tiền claim
-    let claim_amount = user_state.calculate_claim_amount(battle);
+    let principal = vault.shares_to_amount(user_state.shares);
+    let claim_amount = user_state.calculate_claim_amount(battle, vault);
     
     // Kiểm tra vault có đủ tiền
     let vault_balance = vault.to_account_info().lamports();
@@ -476,9 +2332,13 @@ pub fn claim_reward(ctx: Context<ClaimReward>) -> Result<()> {
     
     // Update states
     user_state.claimed = true;
-    user_state.reward_amount = claim_amount.saturating_sub(user_state.amount_staked);
+    user_state.reward_amount = claim_amount.saturating_sub(principal);
     vault.claimed_amount = vault.claimed_amount.saturating_add(claim_amount);
     
+    // TVL giảm theo số tiền rời vault (yield có thể vượt phần đã ghi nhận khi deposit)
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    protocol_state.total_tvl = protocol_state.total_tvl.saturating_sub(claim_amount);
+    
     // Burn ticket tokens
     let user_ticket_balance = ctx.accounts.user_ticket_account.amount;
     if user_ticket_balance > 0 {
@@ -496,7 +2356,7 @@ pub fn claim_reward(ctx: Context<ClaimReward>) -> Result<()> {
         "Claim: User {} claimed {} lamports (principal: {}, reward: {}) from battle {}",
         ctx.accounts.user.key(),
         claim_amount,
-        user_state.amount_staked,
+        principal,
         user_state.reward_amount,
         battle.battle_id
     );
@@ -515,6 +2375,14 @@ pub struct ClaimReward<'info> {
     )]
     pub battle: Account<'info, BattleState>,
     
+    /// Protocol state (counters global)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
     #[account(
         mut,
         seeds = [constants::seeds::USER_STATE, user.key().as_ref(), battle.battle_id.to_le_bytes().as_ref()],
@@ -552,23 +2420,49 @@ pub struct ClaimReward<'info> {
 // WITHDRAW - Rút sớm (có phạt) hoặc rút khi battle bị hủy
 // ============================================================================
 
+/// Phase cho phép withdraw
+/// Sau khi settle, principal chỉ được trả qua `claim_reward`
+pub const WITHDRAW_PHASES: &[BattlePhase] = &[
+    BattlePhase::Scheduled,
+    BattlePhase::Open,
+    BattlePhase::Proposed,
+    BattlePhase::Cancelled,
+];
+
 /// Withdraw - rút sớm trước khi battle kết thúc
-/// Có penalty 1% nếu rút sớm
+/// Có early withdrawal penalty (snapshot khi tạo battle) nếu rút sớm
 pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
     let battle = &mut ctx.accounts.battle;
     let user_state = &mut ctx.accounts.user_state;
     let vault = &mut ctx.accounts.vault;
+    let clock = Clock::get()?;
+    
+    // Kiểm tra battle đang diễn ra hoặc đã bị hủy
+    battle.sync_phase(clock.unix_timestamp)?;
+    battle.require_phase(WITHDRAW_PHASES, clock.unix_timestamp)?;
+    
+    // Chỉ rút được khi kết quả đang đề xuất là force_resolve (trong timelock)
+    require!(
+        battle.phase != BattlePhase::Proposed || battle.force_resolved,
+        MemeWarsError::InvalidBattlePhase
+    );
     
     // Kiểm tra chưa claim
     require!(!user_state.claimed, MemeWarsError::AlreadyClaimed);
     
-    // Tính số tiền rút
-    let mut withdraw_amount = user_state.amount_staked;
+    // Tính số tiền rút: giá trị hiện tại của shares (có thể khác principal do lãi/lỗ)
+    let mut withdraw_amount = vault.shares_to_amount(user_state.shares);
     
-    // Nếu battle đang active (rút sớm) -> áp dụng penalty
-    if battle.is_active() {
+    // Nếu battle chưa bị hủy hoặc force_resolve (rút sớm) -> áp dụng penalty
+    // Rút sớm bị đóng băng trong lock window
+    if !battle.is_refund_window() {
+        require!(
+            !battle.is_locked(clock.unix_timestamp),
+            MemeWarsError::BattleLocked
+        );
+        
         let penalty = withdraw_amount
-            .checked_mul(fees::EARLY_WITHDRAWAL_PENALTY_BPS)
+            .checked_mul(battle.early_withdrawal_penalty_bps)
             .ok_or(MemeWarsError::Overflow)?
             .checked_div(fees::BPS_DIVISOR)
             .ok_or(MemeWarsError::Overflow)?;
@@ -587,18 +2481,49 @@ pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
     **ctx.accounts.user.to_account_info().try_borrow_mut_lamports()? += withdraw_amount;
     
     // Update battle totals
-    if user_state.team == team::TEAM_A {
-        battle.total_staked_a = battle.total_staked_a.saturating_sub(user_state.amount_staked);
-    } else {
-        battle.total_staked_b = battle.total_staked_b.saturating_sub(user_state.amount_staked);
+    battle.sub_team_stake(user_state.team, user_state.amount_staked)?;
+    battle.sub_team_stake_seconds(user_state.team, user_state.stake_seconds)?;
+    
+    // Update vault: redeem shares
+    // Penalty ở lại vault và tăng giá trị share cho những người còn lại
+    vault.total_amount = vault.total_amount.saturating_sub(withdraw_amount);
+    vault.total_shares = vault.total_shares.saturating_sub(user_state.shares);
+    
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    protocol_state.total_tvl = protocol_state.total_tvl.saturating_sub(withdraw_amount);
+    
+    // Người cuối cùng đã rút: phần còn lại (penalty, dư làm tròn) vào treasury
+    // thay vì để depositor tiếp theo nhận trọn
+    let orphaned = vault.take_orphaned_amount();
+    if orphaned > 0 {
+        let vault_info = vault.to_account_info();
+        let rent = Rent::get()?;
+        let swept = orphaned.min(
+            vault_info
+                .lamports()
+                .saturating_sub(rent.minimum_balance(vault_info.data_len())),
+        );
+        if swept > 0 {
+            **vault_info.try_borrow_mut_lamports()? -= swept;
+            **ctx.accounts.treasury.to_account_info().try_borrow_mut_lamports()? += swept;
+            record_collected_fees(
+                protocol_state,
+                &mut ctx.accounts.treasury,
+                &mut ctx.accounts.insurance_fund,
+                swept,
+            )?;
+        }
+        protocol_state.total_tvl = protocol_state.total_tvl.saturating_sub(orphaned);
+        
+        msg!("Swept {} orphaned lamports from emptied vault to treasury", swept);
     }
     
-    // Update vault
-    vault.total_amount = vault.total_amount.saturating_sub(user_state.amount_staked);
-    
-    // Mark as claimed
+    // Mark as claimed, stake đã rút hết không còn tính vào battle
     user_state.claimed = true;
     user_state.reward_amount = 0;
+    user_state.shares = 0;
+    user_state.amount_staked = 0;
+    user_state.stake_seconds = 0;
     
     // Burn ticket tokens
     let user_ticket_balance = ctx.accounts.user_ticket_account.amount;
@@ -628,6 +2553,7 @@ pub struct Withdraw<'info> {
     #[account(mut)]
     pub user: Signer<'info>,
     
+    /// Không kiểm tra pause: rút principal luôn khả dụng
     #[account(
         mut,
         seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
@@ -635,6 +2561,29 @@ pub struct Withdraw<'info> {
     )]
     pub battle: Account<'info, BattleState>,
     
+    /// Protocol state (counters global)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    /// Treasury nhận phần còn lại khi vault không còn shares
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_TREASURY],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, ProtocolTreasury>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::INSURANCE_FUND],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    
     #[account(
         mut,
         seeds = [constants::seeds::USER_STATE, user.key().as_ref(), battle.battle_id.to_le_bytes().as_ref()],
@@ -668,7 +2617,337 @@ pub struct Withdraw<'info> {
     pub system_program: Program<'info, System>,
 }
 
-// Helper functions are now in pyth.rs module
+// ============================================================================
+// CANCEL BATTLE
+// ============================================================================
+
+/// Phase mà authority có thể hủy battle (chưa settle)
+/// NeedsReview chỉ hủy được sau review deadline (hoặc bởi authority)
+pub const CANCEL_PHASES: &[BattlePhase] = &[
+    BattlePhase::Scheduled,
+    BattlePhase::Open,
+    BattlePhase::Locked,
+    BattlePhase::AwaitingSettlement,
+    BattlePhase::NeedsReview,
+];
+
+/// Hủy battle, mọi người rút lại toàn bộ principal qua `withdraw` không bị penalty
+/// Authority hủy bất kỳ lúc nào trước khi settle; người khác chỉ khi thỏa điều kiện
+/// (xem `BattleState::permissionless_cancel_reason`)
+pub fn cancel_battle(ctx: Context<CancelBattle>) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let caller = ctx.accounts.caller.key();
+    let clock = Clock::get()?;
+    
+    battle.sync_phase(clock.unix_timestamp)?;
+    battle.require_phase(CANCEL_PHASES, clock.unix_timestamp)?;
+    
+    let reason = match battle.permissionless_cancel_reason(clock.unix_timestamp) {
+        Some(reason) => reason,
+        None => {
+            require!(caller == battle.authority, MemeWarsError::Unauthorized);
+            constants::cancel_reason::AUTHORITY
+        }
+    };
+    
+    // Vault không có lending position mở (deposit chưa gửi vào lending),
+    // toàn bộ principal vẫn nằm trong vault để hoàn trả qua `withdraw`
+    battle.cancel(reason)?;
+    refund_dispute_bond(battle, ctx.accounts.proposer.as_ref())?;
+    
+    msg!(
+        "Battle {} cancelled by {} | Reason: {} | Total stake to refund: {}",
+        battle.battle_id,
+        caller,
+        reason,
+        battle.total_staked()
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CancelBattle<'info> {
+    /// Authority của battle, hoặc bất kỳ ai khi thỏa điều kiện hủy
+    pub caller: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Người đề xuất cuối cùng, nhận lại dispute bond đang giữ (bắt buộc nếu có bond)
+    /// CHECK: Chỉ nhận lamports, phải khớp với `battle.settled_by`
+    #[account(mut, address = battle.settled_by @ MemeWarsError::Unauthorized)]
+    pub proposer: Option<UncheckedAccount<'info>>,
+}
+
+/// Hoàn dispute bond đang giữ cho người đề xuất cuối cùng khi battle bị hủy
+/// (battle hủy từ NeedsReview không còn finalize để trả bond)
+fn refund_dispute_bond(
+    battle: &mut Account<BattleState>,
+    proposer: Option<&UncheckedAccount>,
+) -> Result<()> {
+    let bond = battle.dispute_bond;
+    if bond == 0 {
+        return Ok(());
+    }
+    let proposer = proposer.ok_or(MemeWarsError::Unauthorized)?;
+    **battle.to_account_info().try_borrow_mut_lamports()? -= bond;
+    **proposer.to_account_info().try_borrow_mut_lamports()? += bond;
+    battle.dispute_bond = 0;
+    
+    msg!("Dispute bond {} refunded to {}", bond, proposer.key());
+    Ok(())
+}
+
+// ============================================================================
+// EMERGENCY UNWIND - Thoát khẩn cấp khi lending protocol gặp sự cố
+// ============================================================================
+
+/// Phase cho phép emergency unwind (chưa settle và không có đề xuất đang chờ finalize,
+/// hoặc đã hủy)
+pub const UNWIND_PHASES: &[BattlePhase] = &[
+    BattlePhase::Scheduled,
+    BattlePhase::Open,
+    BattlePhase::Locked,
+    BattlePhase::AwaitingSettlement,
+    BattlePhase::NeedsReview,
+    BattlePhase::Cancelled,
+];
+
+/// Admin hủy battle và ghi nhận giá trị các vault bằng số tiền thu hồi được
+/// 
+/// Vault truyền qua `remaining_accounts`. Vault có mSOL phải theo sau bởi
+/// `lending::marinade::LIQUID_UNSTAKE_ACCOUNTS` account Marinade (bắt đầu bằng
+/// `lending_position`) để liquid unstake về vault trước khi ghi nhận; thiếu thì reject,
+/// lending position vẫn được giữ nguyên. Chỉ phần thực sự không thu hồi được sau khi
+/// unstake mới bị ghi giảm. Sau đó người chơi rút phần pro-rata qua
+/// `withdraw`, không penalty, có thể thấp hơn principal.
+/// Có thể gọi lại cho các vault còn lại sau khi battle đã bị hủy.
+pub fn emergency_unwind<'info>(
+    ctx: Context<'_, '_, 'info, 'info, EmergencyUnwind<'info>>,
+) -> Result<()> {
+    let battle = &mut ctx.accounts.battle;
+    let clock = Clock::get()?;
+    
+    battle.require_phase(UNWIND_PHASES, clock.unix_timestamp)?;
+    if battle.phase != BattlePhase::Cancelled {
+        battle.cancel(constants::cancel_reason::EMERGENCY_UNWIND)?;
+    }
+    refund_dispute_bond(battle, ctx.accounts.proposer.as_ref())?;
+    
+    let rent = Rent::get()?;
+    let mut total_loss: u64 = 0;
+    let mut accounts = ctx.remaining_accounts;
+    while let Some((vault_info, rest)) = accounts.split_first() {
+        accounts = rest;
+        let mut vault: Account<Vault> = Account::try_from(vault_info)?;
+        require!(
+            vault.battle_id == battle.battle_id
+                && vault_info.key() == vault_address(battle.battle_id, vault.team, ctx.program_id),
+            MemeWarsError::InvalidVault
+        );
+        
+        // Rút mSOL về vault, bắt buộc truyền account Marinade đi kèm
+        if vault.msol_balance > 0 {
+            let unstake_accounts = accounts
+                .get(..lending::marinade::LIQUID_UNSTAKE_ACCOUNTS)
+                .filter(|unstake| vault.lending_position == Some(unstake[0].key()))
+                .ok_or(lending::LendingError::MissingAccounts)?;
+            accounts = &accounts[lending::marinade::LIQUID_UNSTAKE_ACCOUNTS..];
+            let battle_id_bytes = battle.battle_id.to_le_bytes();
+            let team_bytes = vault.team.to_le_bytes();
+            let seeds: &[&[u8]] = &[
+                constants::seeds::VAULT,
+                battle_id_bytes.as_ref(),
+                team_bytes.as_ref(),
+                &[vault.bump],
+            ];
+            lending::marinade::liquid_unstake_from_marinade(
+                vault_info,
+                vault.msol_balance,
+                unstake_accounts,
+                seeds,
+            )?;
+            vault.msol_balance = 0;
+        }
+        
+        let recovered = vault_info
+            .lamports()
+            .saturating_sub(rent.minimum_balance(vault_info.data_len()));
+        let loss = vault.write_down_to(recovered);
+        total_loss = total_loss.saturating_add(loss);
+        
+        msg!(
+            "Battle {} vault {} unwound: recovered {} lamports, loss {}",
+            battle.battle_id,
+            vault.team,
+            vault.total_amount,
+            loss
+        );
+        vault.exit(ctx.program_id)?;
+    }
+    
+    let protocol_state = &mut ctx.accounts.protocol_state;
+    protocol_state.total_tvl = protocol_state.total_tvl.saturating_sub(total_loss);
+    
+    msg!(
+        "Battle {} emergency unwind by {} | Cancel reason: {} | Total loss: {}",
+        battle.battle_id,
+        ctx.accounts.authority.key(),
+        battle.cancel_reason,
+        total_loss
+    );
+    
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct EmergencyUnwind<'info> {
+    /// Admin của protocol (sự cố lending là sự cố cấp protocol)
+    pub authority: Signer<'info>,
+    
+    /// Protocol state (roles, TVL)
+    #[account(
+        mut,
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump,
+        has_one = authority @ MemeWarsError::Unauthorized
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+    
+    #[account(
+        mut,
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    /// Người đề xuất cuối cùng, nhận lại dispute bond đang giữ (bắt buộc nếu có bond)
+    /// CHECK: Chỉ nhận lamports, phải khớp với `battle.settled_by`
+    #[account(mut, address = battle.settled_by @ MemeWarsError::Unauthorized)]
+    pub proposer: Option<UncheckedAccount<'info>>,
+}
+
+// ============================================================================
+// GET BATTLE SUMMARY - View cho client
+// ============================================================================
+
+/// Trả về tóm tắt battle qua return data (dùng với simulate/view)
+/// Được gọi ở mọi phase
+pub fn get_battle_summary(ctx: Context<GetBattleSummary>) -> Result<BattleSummary> {
+    let clock = Clock::get()?;
+    Ok(ctx.accounts.battle.summary(clock.unix_timestamp))
+}
+
+#[derive(Accounts)]
+pub struct GetBattleSummary<'info> {
+    #[account(
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+}
+
+// ============================================================================
+// GET INSURANCE COVERAGE - View mức bảo hiểm principal của battle
+// ============================================================================
+
+/// Trả về coverage của insurance fund cho battle (client đọc qua simulate)
+pub fn get_insurance_coverage(ctx: Context<GetInsuranceCoverage>) -> Result<CoverageSummary> {
+    Ok(ctx.accounts.protocol_state.coverage(&ctx.accounts.battle))
+}
+
+#[derive(Accounts)]
+pub struct GetInsuranceCoverage<'info> {
+    #[account(
+        seeds = [constants::seeds::BATTLE, battle.battle_id.to_le_bytes().as_ref()],
+        bump = battle.bump
+    )]
+    pub battle: Account<'info, BattleState>,
+    
+    #[account(
+        seeds = [constants::seeds::PROTOCOL_STATE],
+        bump = protocol_state.bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+}
+
+// ============================================================================
+// HELPERS
+// ============================================================================
+
+/// Kiểm tra thời lượng battle nằm trong giới hạn cho phép
+fn validate_duration(duration_seconds: i64) -> Result<()> {
+    require!(
+        duration_seconds >= constants::time::MIN_BATTLE_DURATION,
+        MemeWarsError::InvalidDuration
+    );
+    require!(
+        duration_seconds <= constants::time::MAX_BATTLE_DURATION,
+        MemeWarsError::InvalidDuration
+    );
+    Ok(())
+}
+
+/// Keeper bounty: KEEPER_BOUNTY_BPS của yield, tối đa MAX_KEEPER_BOUNTY và protocol fee
+fn keeper_bounty(total_yield: u64, protocol_fee: u64) -> Result<u64> {
+    let bounty = total_yield
+        .checked_mul(fees::KEEPER_BOUNTY_BPS)
+        .ok_or(MemeWarsError::Overflow)?
+        .checked_div(fees::BPS_DIVISOR)
+        .ok_or(MemeWarsError::Overflow)?;
+    Ok(bounty.min(fees::MAX_KEEPER_BOUNTY).min(protocol_fee))
+}
+
+/// Xác định start_time thực tế và battle có phải lên lịch trước không
+fn resolve_start_time(schedule: &ScheduleParams, now: i64) -> Result<(i64, bool)> {
+    if schedule.start_time <= now {
+        return Ok((now, false));
+    }
+    require!(
+        schedule.start_time - now <= constants::time::MAX_SCHEDULE_AHEAD,
+        MemeWarsError::InvalidStartTime
+    );
+    Ok((schedule.start_time, true))
+}
+
+/// Lock window phải ngắn hơn thời lượng battle
+fn validate_lock_period(lock_period_seconds: i64, duration_seconds: i64) -> Result<()> {
+    require!(
+        (0..duration_seconds).contains(&lock_period_seconds),
+        MemeWarsError::InvalidLockPeriod
+    );
+    Ok(())
+}
+
+/// Kiểm tra scoring mode được hỗ trợ
+fn validate_scoring_mode(scoring_mode: u8) -> Result<()> {
+    require!(
+        scoring_mode == constants::scoring::LINEAR
+            || scoring_mode == constants::scoring::LOG_RETURN,
+        MemeWarsError::InvalidScoringMode
+    );
+    Ok(())
+}
+
+/// PDA của vault cho một phe trong battle
+pub fn vault_address(battle_id: u64, team: u8, program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            constants::seeds::VAULT,
+            battle_id.to_le_bytes().as_ref(),
+            team.to_le_bytes().as_ref(),
+        ],
+        program_id,
+    )
+    .0
+}
+
+// Pyth helper functions are in pyth.rs module
 
 // ============================================================================
 // ERROR CODES
@@ -720,4 +2999,85 @@ pub enum MemeWarsError {
     
     #[msg("Price confidence too low")]
     LowPriceConfidence,
+    
+    #[msg("Invalid bracket configuration")]
+    InvalidBrackets,
+    
+    #[msg("Handicap out of range")]
+    InvalidHandicap,
+    
+    #[msg("Tie tolerance out of range")]
+    InvalidTieTolerance,
+    
+    #[msg("Unsupported scoring mode")]
+    InvalidScoringMode,
+    
+    #[msg("Instruction not allowed in the current battle phase")]
+    InvalidBattlePhase,
+    
+    #[msg("Illegal battle phase transition")]
+    IllegalPhaseTransition,
+    
+    #[msg("Invalid battle start time")]
+    InvalidStartTime,
+    
+    #[msg("Battle has not started yet")]
+    BattleNotStarted,
+    
+    #[msg("Invalid lock period")]
+    InvalidLockPeriod,
+    
+    #[msg("Battle is in the lock-out window")]
+    BattleLocked,
+    
+    #[msg("Dispute window has closed")]
+    DisputeWindowClosed,
+    
+    #[msg("Dispute window is still open")]
+    DisputeWindowOpen,
+    
+    #[msg("Dispute evidence does not change the proposed result")]
+    DisputeRejected,
+    
+    #[msg("Settlement has not been finalized yet")]
+    SettlementNotFinalized,
+    
+    #[msg("Review deadline has passed")]
+    ReviewExpired,
+    
+    #[msg("Invalid keeper set")]
+    InvalidKeeperSet,
+    
+    #[msg("Signer is not a registered keeper")]
+    NotAKeeper,
+    
+    #[msg("Keeper has already attested")]
+    AlreadyAttested,
+    
+    #[msg("Invalid attested values")]
+    InvalidAttestation,
+    
+    #[msg("Battle must be settled by keeper attestation")]
+    KeeperAttestationRequired,
+    
+    #[msg("Config value exceeds the allowed maximum")]
+    InvalidConfig,
+    
+    #[msg("Invalid role assignment")]
+    InvalidRole,
+    
+    #[msg("Protocol is paused")]
+    ProtocolPaused,
+    
+    #[msg("Battle is paused")]
+    BattlePaused,
+    
+    #[msg("Start window has expired, battle can only be cancelled")]
+    StartWindowExpired,
+    
+    #[msg("Settlement price was not published close enough to end_time")]
+    SettlementPriceOutOfWindow,
+    
+    #[msg("Force resolve window has expired, battle can only be cancelled")]
+    ForceResolveWindowExpired,
 }
@@ -1,7 +1,7 @@
-use anchor_lang::prelude::*;
+//! Lending protocol integration module
+//! Hỗ trợ tích hợp với Marginfi, Kamino, hoặc Marinade Finance
 
-/// Lending protocol integration module
-/// Hỗ trợ tích hợp với Marginfi, Kamino, hoặc Marinade Finance
+use anchor_lang::prelude::*;
 
 /// Marinade Finance - Native SOL staking
 /// Đơn giản và ổn định, tốt cho MVP
@@ -9,7 +9,7 @@ pub mod marinade {
     use super::*;
     use anchor_lang::solana_program::{
         instruction::{AccountMeta, Instruction},
-        program::invoke,
+        program::{invoke, invoke_signed},
     };
 
     /// Marinade Finance Program ID (Mainnet)
@@ -20,6 +20,15 @@ pub mod marinade {
     /// Instruction format: discriminator (u8) + lamports (u64)
     const MARINADE_DEPOSIT_DISCRIMINATOR: u8 = 0;
 
+    /// Anchor discriminator của `liquid_unstake`: sha256("global:liquid_unstake")[..8]
+    /// Instruction format: discriminator (8 bytes) + msol_amount (u64)
+    const MARINADE_LIQUID_UNSTAKE_DISCRIMINATOR: [u8; 8] = [30, 30, 119, 240, 191, 227, 12, 16];
+
+    /// Số account Marinade cần cho `liquid_unstake_from_marinade`, theo thứ tự:
+    /// m_sol_token_account, marinade_program, marinade_state, m_sol_mint,
+    /// liq_pool_sol, liq_pool_msol, treasury_msol, system_program, token_program
+    pub const LIQUID_UNSTAKE_ACCOUNTS: usize = 9;
+
     /// Deposit SOL vào Marinade Finance để nhận mSOL
     /// 
     /// # Arguments
@@ -39,6 +48,7 @@ pub mod marinade {
     /// 
     /// # Returns
     /// * `Result<Pubkey>` - mSOL token account pubkey (lending position)
+    #[allow(clippy::too_many_arguments)]
     pub fn deposit_to_marinade<'info>(
         vault: &AccountInfo<'info>,
         amount: u64,
@@ -110,6 +120,88 @@ pub mod marinade {
         
         Ok(m_sol_token_account_key)
     }
+
+    /// Rút mSOL của vault về SOL qua liquidity pool của Marinade (liquid unstake)
+    /// 
+    /// SOL nhận được chuyển thẳng vào vault. Vault PDA ký với `signer_seeds`.
+    /// 
+    /// # Arguments
+    /// * `vault` - Vault PDA sở hữu mSOL token account, nhận SOL
+    /// * `msol_amount` - Số mSOL cần unstake
+    /// * `accounts` - LIQUID_UNSTAKE_ACCOUNTS account Marinade theo thứ tự đã mô tả
+    /// * `signer_seeds` - Seeds của vault PDA
+    pub fn liquid_unstake_from_marinade<'info>(
+        vault: &AccountInfo<'info>,
+        msol_amount: u64,
+        accounts: &[AccountInfo<'info>],
+        signer_seeds: &[&[u8]],
+    ) -> Result<()> {
+        let [
+            m_sol_token_account,
+            marinade_program,
+            marinade_state,
+            m_sol_mint,
+            liq_pool_sol,
+            liq_pool_msol,
+            treasury_msol,
+            system_program,
+            token_program,
+        ] = accounts
+        else {
+            return Err(anchor_lang::error!(LendingError::MissingAccounts));
+        };
+        require_keys_eq!(
+            *marinade_program.key,
+            MARINADE_PROGRAM_ID.parse::<Pubkey>().unwrap(),
+            LendingError::WithdrawalFailed
+        );
+
+        msg!("Liquid unstaking {} mSOL from Marinade Finance", msol_amount);
+
+        let mut instruction_data = Vec::with_capacity(16);
+        instruction_data.extend_from_slice(&MARINADE_LIQUID_UNSTAKE_DISCRIMINATOR);
+        instruction_data.extend_from_slice(&msol_amount.to_le_bytes());
+
+        // Accounts theo thứ tự của Marinade liquid_unstake instruction
+        let metas = vec![
+            AccountMeta::new(*marinade_state.key, false),
+            AccountMeta::new(*m_sol_mint.key, false),
+            AccountMeta::new(*liq_pool_sol.key, false),
+            AccountMeta::new(*liq_pool_msol.key, false),
+            AccountMeta::new(*treasury_msol.key, false),
+            AccountMeta::new(*m_sol_token_account.key, false), // get_msol_from
+            AccountMeta::new_readonly(*vault.key, true), // get_msol_from_authority (vault PDA)
+            AccountMeta::new(*vault.key, false), // transfer_sol_to
+            AccountMeta::new_readonly(*system_program.key, false),
+            AccountMeta::new_readonly(*token_program.key, false),
+        ];
+
+        let instruction = Instruction {
+            program_id: *marinade_program.key,
+            accounts: metas,
+            data: instruction_data,
+        };
+
+        invoke_signed(
+            &instruction,
+            &[
+                marinade_state.clone(),
+                m_sol_mint.clone(),
+                liq_pool_sol.clone(),
+                liq_pool_msol.clone(),
+                treasury_msol.clone(),
+                m_sol_token_account.clone(),
+                vault.clone(),
+                system_program.clone(),
+                token_program.clone(),
+                marinade_program.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+
+        msg!("Successfully unstaked {} mSOL from Marinade", msol_amount);
+        Ok(())
+    }
 }
 
 pub mod marginfi {
@@ -309,7 +401,7 @@ pub fn deposit_to_lending<'info>(
                     token_program,
                 )
             } else {
-                return Err(anchor_lang::error!(LendingError::MissingAccounts));
+                Err(anchor_lang::error!(LendingError::MissingAccounts))
             }
         }
         LendingProtocol::Marginfi => {
@@ -327,7 +419,7 @@ pub fn deposit_to_lending<'info>(
                     marginfi_program,
                 )
             } else {
-                return Err(anchor_lang::error!(LendingError::MissingAccounts));
+                Err(anchor_lang::error!(LendingError::MissingAccounts))
             }
         }
         LendingProtocol::Kamino => {
@@ -345,7 +437,7 @@ pub fn deposit_to_lending<'info>(
                     kamino_program,
                 )
             } else {
-                return Err(anchor_lang::error!(LendingError::MissingAccounts));
+                Err(anchor_lang::error!(LendingError::MissingAccounts))
             }
         }
     }
@@ -3,10 +3,12 @@ use anchor_lang::prelude::*;
 pub mod constants;
 pub mod instructions;
 pub mod lending;
+pub mod math;
 pub mod pyth;
 pub mod state;
 
 use instructions::*;
+use state::{BattleSummary, ConfigParams, CoverageSummary, RoleParams, SafetyParams, ScheduleParams, ScoringParams};
 
 declare_id!("71r5LdZhJUpLaNJvCeSxmRqzNmcJuiM8XQ7U8AQdKHGB");
 
@@ -14,10 +16,76 @@ declare_id!("71r5LdZhJUpLaNJvCeSxmRqzNmcJuiM8XQ7U8AQdKHGB");
 pub mod memewars {
     use super::*;
 
-    /// Initialize the protocol (optional, for future admin features)
-    pub fn initialize(_ctx: Context<Initialize>) -> Result<()> {
-        msg!("MemeWars program initialized");
-        Ok(())
+    /// Khởi tạo protocol: tạo ProtocolState, ProtocolTreasury và InsuranceFund PDA singleton
+    /// 
+    /// ProtocolState lưu các counter global (số battle, TVL, tổng fee) và phải
+    /// được tạo trước khi tạo battle. Treasury nhận protocol fees khi settle,
+    /// InsuranceFund nhận một phần fee để bù thiếu hụt principal.
+    /// Chỉ upgrade authority của program được gọi; người gọi trở thành admin,
+    /// đồng thời giữ role settler và treasury manager.
+    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+        instructions::initialize(ctx)
+    }
+
+    /// Admin cập nhật protocol fee, early withdrawal penalty và tham số insurance fund
+    /// 
+    /// # Arguments
+    /// * `config` - Tham số mới, bị giới hạn bởi MAX_PROTOCOL_FEE_BPS và
+    ///   MAX_EARLY_WITHDRAWAL_PENALTY_BPS; tỷ lệ insurance tối đa 10_000 bps
+    /// 
+    /// Fee và penalty chỉ áp dụng cho battle tạo sau này, battle đang chạy giữ tham số
+    /// lúc tạo. Tỷ lệ insurance và coverage áp dụng ngay từ lần thu fee/settle tiếp theo
+    pub fn update_config(ctx: Context<UpdateConfig>, config: ConfigParams) -> Result<()> {
+        instructions::update_config(ctx, config)
+    }
+
+    /// Admin đặt các role của protocol
+    /// 
+    /// # Arguments
+    /// * `roles`:
+    ///   - `battle_creator`: được tạo battle (Pubkey::default() = bất kỳ ai)
+    ///   - `settler`: keeper/settler, xử lý review và force_resolve của mọi battle
+    ///   - `treasury_manager`: rút protocol fees
+    pub fn set_roles(ctx: Context<SetRoles>, roles: RoleParams) -> Result<()> {
+        instructions::set_roles(ctx, roles)
+    }
+
+    /// Bước 1 chuyển giao admin: đề xuất admin mới
+    /// 
+    /// Quyền chỉ chuyển khi admin mới gọi `accept_authority`
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        instructions::propose_authority(ctx, new_authority)
+    }
+
+    /// Bước 2 chuyển giao admin: admin mới (pending_authority) nhận quyền
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        instructions::accept_authority(ctx)
+    }
+
+    /// Emergency pause toàn protocol (chỉ admin)
+    /// 
+    /// Khi pause: không tạo battle và deposit được. Withdraw, claim và settle
+    /// vẫn hoạt động để người chơi luôn rút được principal.
+    pub fn set_protocol_paused(ctx: Context<SetProtocolPaused>, paused: bool) -> Result<()> {
+        instructions::set_protocol_paused(ctx, paused)
+    }
+
+    /// Emergency pause một battle (authority của battle hoặc admin)
+    /// 
+    /// Khi pause: battle không nhận deposit. Withdraw, claim và settle vẫn hoạt động.
+    pub fn set_battle_paused(ctx: Context<SetBattlePaused>, paused: bool) -> Result<()> {
+        instructions::set_battle_paused(ctx, paused)
+    }
+
+    /// Đổi authority của một battle
+    /// 
+    /// # Requirements
+    /// - Signer là authority hiện tại của battle hoặc admin của protocol
+    pub fn transfer_battle_authority(
+        ctx: Context<TransferBattleAuthority>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        instructions::transfer_battle_authority(ctx, new_authority)
     }
 
     /// Tạo một cuộc chiến mới giữa 2 token
@@ -25,51 +93,255 @@ pub mod memewars {
     /// # Arguments
     /// * `battle_id` - ID duy nhất của cuộc chiến
     /// * `duration_seconds` - Thời gian diễn ra cuộc chiến (1-30 ngày)
+    /// * `scoring` - Cách xác định winner:
+    ///   - `handicap_bps`: cộng vào growth của token B (0 = không handicap)
+    ///   - `tie_tolerance_bps`: draw band, chênh lệch growth trong khoảng này thì hòa
+    ///   - `confidence_ties`: hòa nếu khoảng Pyth confidence của 2 token chồng lên nhau
+    ///   - `scoring_mode`: 0 = Linear growth, 1 = Log return
+    /// * `schedule` - Thời gian bắt đầu (<= hiện tại thì bắt đầu ngay), pre-season deposits và lock period trước end_time
+    /// * `safety` - Giới hạn an toàn khi settle:
+    ///   - `min_team_stake`: stake tối thiểu (lamports) mỗi phe, dưới ngưỡng thì battle tự hủy
+    ///   - `max_growth_bps`: circuit breaker theo |growth| (0 = tắt)
+    ///   - `max_ema_divergence_bps`: circuit breaker theo độ lệch giá cuối với EMA (0 = tắt)
     /// 
     /// # Accounts required
     /// * `authority` - Người tạo battle (signer, payer)
     /// * `battle` - PDA lưu thông tin battle
     /// * `token_a` - Mint address của token phe A
     /// * `token_b` - Mint address của token phe B  
-    /// * `price_feed_a` - Pyth price feed cho token A
-    /// * `price_feed_b` - Pyth price feed cho token B
+    /// * `price_feed_a` - Pyth price feed cho token A (thuộc Pyth receiver, feed ID được lưu lại)
+    /// * `price_feed_b` - Pyth price feed cho token B (thuộc Pyth receiver, feed ID được lưu lại)
     pub fn create_battle(
         ctx: Context<CreateBattle>,
         battle_id: u64,
         duration_seconds: i64,
+        scoring: ScoringParams,
+        schedule: ScheduleParams,
+        safety: SafetyParams,
     ) -> Result<()> {
-        instructions::create_battle(ctx, battle_id, duration_seconds)
+        instructions::create_battle(
+            ctx,
+            battle_id,
+            duration_seconds,
+            scoring,
+            schedule,
+            safety,
+        )
+    }
+
+    /// Tạo bracket battle - đoán % tăng trưởng của một token
+    /// 
+    /// # Arguments
+    /// * `battle_id` - ID duy nhất của cuộc chiến
+    /// * `duration_seconds` - Thời gian diễn ra cuộc chiến (1-30 ngày)
+    /// * `bracket_bounds` - Ranh giới growth (bps) tăng dần, N ranh giới = N + 1 bracket
+    /// * `scoring_mode` - 0 = Linear growth, 1 = Log return
+    /// * `schedule` - Thời gian bắt đầu, pre-season deposits và lock period
+    /// * `safety` - Stake tối thiểu của ít nhất 2 bracket và giới hạn circuit breaker
+    /// 
+    /// # Logic
+    /// - Mỗi bracket là một phe (team = 1..=N+1) với vault riêng
+    /// - Khi settle, bracket chứa growth thực tế là phe thắng
+    pub fn create_bracket_battle(
+        ctx: Context<CreateBracketBattle>,
+        battle_id: u64,
+        duration_seconds: i64,
+        bracket_bounds: Vec<i64>,
+        scoring_mode: u8,
+        schedule: ScheduleParams,
+        safety: SafetyParams,
+    ) -> Result<()> {
+        instructions::create_bracket_battle(
+            ctx,
+            battle_id,
+            duration_seconds,
+            bracket_bounds,
+            scoring_mode,
+            schedule,
+            safety,
+        )
+    }
+
+    /// Bắt đầu battle đã lên lịch (permissionless crank)
+    /// 
+    /// # Logic
+    /// 1. Kiểm tra đang trong [start_time, start_time + START_WINDOW)
+    /// 2. Chụp giá ban đầu từ Pyth
+    /// 3. Chuyển battle sang Open
+    pub fn start_battle(ctx: Context<StartBattle>) -> Result<()> {
+        instructions::start_battle(ctx)
     }
 
     /// Deposit SOL vào một cuộc chiến và nhận ticket token
     /// 
     /// # Arguments
     /// * `amount` - Số lamports muốn stake
-    /// * `team` - Phe muốn tham gia (1 = Team A, 2 = Team B)
+    /// * `team` - Phe muốn tham gia (1 = Team A, 2 = Team B, bracket battle: số bracket)
     /// 
     /// # Logic
     /// 1. Transfer SOL từ user vào vault
     /// 2. Mint ticket token cho user
     /// 3. Cập nhật user state và battle state
+    /// 
+    /// # Requirements
+    /// - Battle ở phase Open (hoặc Scheduled trước start deadline nếu bật pre-season deposits)
+    /// - Protocol và battle không bị pause
+    /// - Chưa vào lock window trước end_time
+    /// - User chưa rút hoặc claim trong battle này
     pub fn deposit(ctx: Context<Deposit>, amount: u64, team: u8) -> Result<()> {
         instructions::deposit(ctx, amount, team)
     }
 
-    /// Settle battle - kết thúc cuộc chiến và xác định winner
+    /// Đề xuất kết quả battle - bước đầu của optimistic settlement
     /// 
     /// # Logic
-    /// 1. Lấy giá cuối cùng từ Pyth Oracle
+    /// 1. Lấy giá cuối cùng từ Pyth Oracle (publish trong ±60 giây quanh end_time)
     /// 2. Tính % tăng trưởng của mỗi token
-    /// 3. Xác định winner (token có % growth cao hơn)
-    /// 4. Tính và phân bổ yield cho winners
-    /// 5. Thu protocol fee (5%)
+    /// 3. Xác định winner (token có % growth cao hơn, hoặc bracket chứa growth)
+    ///    Hòa nếu chênh lệch nằm trong draw band hoặc trong sai số confidence
+    /// 4. Mở dispute window (DISPUTE_WINDOW)
+    ///    Nếu growth hoặc độ lệch với EMA vượt giới hạn -> NeedsReview chờ admin
+    /// 
+    /// Battle một phía hoặc dưới `min_team_stake` sẽ tự hủy thay vì settle,
+    /// mọi người rút lại principal + yield qua `withdraw`
+    /// 
+    /// # Requirements
+    /// - Battle ở phase AwaitingSettlement (thời gian hiện tại >= end_time)
+    /// - Bất kỳ ai cũng có thể đề xuất
+    /// - Price update phải cùng feed ID đã lưu khi tạo battle, giá publish quanh end_time
+    ///   (dùng price feed của battle, hoặc `PriceUpdateV2` tại end_time post lên Pyth receiver)
+    /// - Battle không dùng KeeperSet (battle có KeeperSet settle qua `attest_result`)
+    pub fn propose_settlement(ctx: Context<ProposeSettlement>) -> Result<()> {
+        instructions::propose_settlement(ctx)
+    }
+
+    /// Dispute kết quả đang đề xuất bằng giá mới từ Pyth
+    /// 
+    /// # Logic
+    /// 1. Challenger đặt cọc DISPUTE_BOND trước mọi thay đổi state
+    /// 2. Đọc giá từ Pyth price update của các feed của battle (publish quanh end_time,
+    ///    có thể post sau end_time)
+    /// 3. Nếu winner thay đổi: kết quả mới thay thế, dispute window bắt đầu lại,
+    ///    bond của challenger trước (nếu có) chuyển cho challenger mới
+    /// 4. Nếu giá kích hoạt circuit breaker: NeedsReview, bond được giữ lại và trả cho
+    ///    người đề xuất cuối cùng khi finalize (hoặc hoàn lại nếu battle bị hủy)
     /// 
     /// # Requirements
-    /// - Battle phải đang active
-    /// - Thời gian hiện tại >= end_time
-    /// - Chỉ authority mới có thể settle
-    pub fn settle(ctx: Context<SettleBattle>) -> Result<()> {
-        instructions::settle(ctx)
+    /// - Battle ở phase Proposed, còn trong dispute window
+    /// - Bằng chứng phải cho ra winner khác kết quả đang đề xuất
+    pub fn dispute_settlement(ctx: Context<DisputeSettlement>) -> Result<()> {
+        instructions::dispute_settlement(ctx)
+    }
+
+    /// Admin quyết định winner cho battle bị circuit breaker
+    /// 
+    /// # Arguments
+    /// * `winner` - Team thắng (0 = hòa)
+    /// 
+    /// # Requirements
+    /// - Battle ở phase NeedsReview, chưa quá review deadline
+    ///   (quá hạn thì bất kỳ ai cũng có thể hủy battle)
+    /// - Chỉ authority của battle hoặc settler của protocol (nên là multisig)
+    /// - Kết quả finalize được sau DISPUTE_WINDOW (timelock)
+    pub fn resolve_review(ctx: Context<ResolveReview>, winner: u8) -> Result<()> {
+        instructions::resolve_review(ctx, winner)
+    }
+
+    /// Thu phần protocol fee còn thiếu từ một vault của battle đã settle
+    /// 
+    /// Chỉ chuyển lamports vượt quá số tiền còn nợ người chơi. Bất kỳ ai cũng có thể gọi.
+    pub fn collect_protocol_fee(ctx: Context<CollectProtocolFee>) -> Result<()> {
+        instructions::collect_protocol_fee(ctx)
+    }
+
+    /// Treasury manager rút fees từ treasury
+    /// 
+    /// # Arguments
+    /// * `amount` - Số lamports rút (không vượt quá phần trên rent-exempt minimum)
+    pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+        instructions::withdraw_treasury(ctx, amount)
+    }
+
+    /// Nạp thêm vốn cho insurance fund (bất kỳ ai cũng có thể gọi)
+    /// 
+    /// # Arguments
+    /// * `amount` - Số lamports chuyển từ `funder` vào insurance fund, cộng vào `insurance_balance`
+    pub fn fund_insurance(ctx: Context<FundInsurance>, amount: u64) -> Result<()> {
+        instructions::fund_insurance(ctx, amount)
+    }
+
+    /// Admin tuyên bố kết quả khi oracle hỏng vĩnh viễn (feed bị delist)
+    /// 
+    /// # Arguments
+    /// * `winner` - Team thắng (0 = hòa), phải là phe có stake
+    /// 
+    /// # Requirements
+    /// - Battle ở phase AwaitingSettlement, trong FORCE_RESOLVE_WINDOW sau
+    ///   `end_time + SETTLEMENT_GRACE_PERIOD` (sau đó chỉ còn cancel)
+    /// - Chỉ authority của battle hoặc settler của protocol
+    /// - Kết quả finalize được sau FORCE_RESOLVE_TIMELOCK; trong timelock
+    ///   người chơi có thể `withdraw` không mất penalty
+    pub fn force_resolve(ctx: Context<ForceResolve>, winner: u8) -> Result<()> {
+        instructions::force_resolve(ctx, winner)
+    }
+
+    /// Tạo KeeperSet (M-of-N) để attest kết quả battle
+    /// 
+    /// # Arguments
+    /// * `keepers` - Danh sách keeper (tối đa MAX_KEEPERS, không trùng)
+    /// * `threshold` - Số attestation trùng khớp cần thiết (1..=N)
+    pub fn create_keeper_set(
+        ctx: Context<CreateKeeperSet>,
+        keepers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        instructions::create_keeper_set(ctx, keepers, threshold)
+    }
+
+    /// Thay danh sách keeper và threshold (chỉ authority của KeeperSet)
+    pub fn update_keeper_set(
+        ctx: Context<UpdateKeeperSet>,
+        keepers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        instructions::update_keeper_set(ctx, keepers, threshold)
+    }
+
+    /// Keeper attest giá trị cuối của battle dùng KeeperSet
+    /// 
+    /// # Arguments
+    /// * `final_value_a` - Giá trị cuối của token A / metric A
+    /// * `final_value_b` - Giá trị cuối của token B (bỏ qua với bracket battle)
+    /// 
+    /// # Requirements
+    /// - Battle đã hết giờ, ở phase AwaitingSettlement
+    /// - Signer là keeper trong KeeperSet của battle, mỗi keeper attest một lần
+    /// - Đủ M attestation trùng khớp -> battle chuyển sang Proposed, finalize được ngay
+    pub fn attest_result(
+        ctx: Context<AttestResult>,
+        final_value_a: i64,
+        final_value_b: i64,
+    ) -> Result<()> {
+        instructions::attest_result(ctx, final_value_a, final_value_b)
+    }
+
+    /// Finalize kết quả sau dispute window
+    /// 
+    /// # Logic
+    /// 1. Nếu lamports của các vault thấp hơn principal: insurance fund bù trước
+    ///    (tối đa `max_coverage_bps`), còn thiếu thì haircut đều mọi vault
+    ///    theo `recovery_bps` (không có yield)
+    /// 2. Yield = lamports thực có vượt principal, phân bổ cho winners; lamports được
+    ///    chia lại giữa các vault để mỗi vault đủ principal + yield của phe mình
+    /// 3. Thu protocol fee (snapshot khi tạo battle, mặc định 5%) vào treasury và insurance fund, trả keeper bounty và bond cho người đề xuất cuối cùng
+    /// 4. Chuyển battle sang Settled để user claim
+    /// 
+    /// # Requirements
+    /// - Battle ở phase Proposed và đã hết dispute window
+    pub fn finalize_settlement<'info>(
+        ctx: Context<'_, '_, 'info, 'info, FinalizeSettlement<'info>>,
+    ) -> Result<()> {
+        instructions::finalize_settlement(ctx)
     }
 
     /// Claim reward sau khi battle đã settle
@@ -80,7 +352,7 @@ pub mod memewars {
     /// - Tie: cả 2 nhận principal + yield tỷ lệ
     /// 
     /// # Requirements
-    /// - Battle phải đã settle
+    /// - Battle ở phase Settled (đã finalize, hết dispute window)
     /// - User chưa claim
     pub fn claim_reward(ctx: Context<ClaimReward>) -> Result<()> {
         instructions::claim_reward(ctx)
@@ -89,15 +361,65 @@ pub mod memewars {
     /// Withdraw - rút sớm trước khi battle kết thúc
     /// 
     /// # Logic
-    /// - Nếu battle đang active: áp dụng penalty 1%
-    /// - Nếu battle bị cancelled: rút full amount
+    /// - Nếu battle đang Open: áp dụng early withdrawal penalty của battle (mặc định 1%)
+    /// - Nếu battle bị Cancelled hoặc đang trong timelock force_resolve: rút toàn bộ giá trị
+    ///   shares không penalty (sau emergency unwind là phần pro-rata của số tiền thu hồi)
+    /// - Người cuối cùng rút khỏi vault: phần còn lại (penalty, dư làm tròn) vào treasury
     /// 
     /// # Requirements
+    /// - Battle ở phase Scheduled, Open (ngoài lock window), Cancelled, hoặc Proposed
+    ///   bởi force_resolve (sau khi settle phải dùng claim_reward)
     /// - User chưa claim/withdraw
     pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
         instructions::withdraw(ctx)
     }
-}
 
-#[derive(Accounts)]
-pub struct Initialize {}
+    /// Hủy battle và cho phép mọi người rút principal không bị penalty
+    /// 
+    /// # Requirements
+    /// - Battle chưa settle
+    /// - Authority hủy bất kỳ lúc nào; người khác chỉ khi battle không thể tiếp tục
+    ///   (battle lên lịch nhưng không được start trong START_WINDOW sau start_time,
+    ///   hoặc chưa settle và không được force_resolve sau end_time + SETTLEMENT_GRACE_PERIOD
+    ///   + FORCE_RESOLVE_WINDOW, hoặc circuit breaker không được admin xử lý trước
+    ///   review deadline)
+    /// - Nếu battle đang giữ dispute bond: truyền `proposer` (= settled_by) để hoàn bond
+    pub fn cancel_battle(ctx: Context<CancelBattle>) -> Result<()> {
+        instructions::cancel_battle(ctx)
+    }
+
+    /// Emergency unwind khi lending protocol gặp sự cố
+    /// 
+    /// # Logic
+    /// 1. Hủy battle (cancel reason EMERGENCY_UNWIND)
+    /// 2. Với mỗi vault trong `remaining_accounts`: liquid unstake mSOL về vault qua
+    ///    các account Marinade truyền ngay sau vault, rồi ghi nhận giá trị bằng số
+    ///    tiền thu hồi được và đóng lending position
+    /// 3. Người chơi rút phần pro-rata qua `withdraw` (không penalty, có thể thấp hơn principal)
+    /// 
+    /// # Requirements
+    /// - Battle chưa settle và không có đề xuất đang chờ finalize (hoặc đã hủy, để
+    ///   unwind các vault còn lại)
+    /// - Chỉ admin của protocol
+    /// - Vault còn mSOL phải đi kèm account Marinade, nếu không reject
+    /// - Nếu battle đang giữ dispute bond: truyền `proposer` (= settled_by) để hoàn bond
+    pub fn emergency_unwind<'info>(
+        ctx: Context<'_, '_, 'info, 'info, EmergencyUnwind<'info>>,
+    ) -> Result<()> {
+        instructions::emergency_unwind(ctx)
+    }
+
+    /// Xem tóm tắt battle (TVL, thời gian, handicap, growth, winner)
+    /// 
+    /// Không thay đổi state, client gọi qua simulate để đọc return data
+    pub fn get_battle_summary(ctx: Context<GetBattleSummary>) -> Result<BattleSummary> {
+        instructions::get_battle_summary(ctx)
+    }
+
+    /// Xem mức bảo hiểm principal của battle từ insurance fund
+    /// 
+    /// `coverage_bps` = min(coverage tối đa, số dư quỹ) / principal, không thay đổi state
+    pub fn get_insurance_coverage(ctx: Context<GetInsuranceCoverage>) -> Result<CoverageSummary> {
+        instructions::get_insurance_coverage(ctx)
+    }
+}
@@ -0,0 +1,258 @@
+//! Fixed-point math cho settlement
+//!
+//! Mọi phép tính growth dùng i128 với checked operations, độ chính xác 10^9
+//! (1.0 = 100% = `GROWTH_SCALE`), cao hơn nhiều so với basis points.
+
+/// Scale của growth: 1_000_000_000 = 100%
+pub const GROWTH_SCALE: i128 = 1_000_000_000;
+
+/// Số đơn vị growth scaled trong 1 basis point
+pub const SCALED_PER_BPS: i128 = GROWTH_SCALE / 10_000;
+
+/// Scale nội bộ cho logarithm (10^18) để giữ độ chính xác trước khi làm tròn về 10^9
+const LN_SCALE: i128 = 1_000_000_000_000_000_000;
+
+/// ln(2) scaled by 10^18
+const LN_2: i128 = 693_147_180_559_945_309;
+
+/// Số term của chuỗi atanh, đủ cho sai số < 10^-18 với z <= 1/3
+const LN_SERIES_TERMS: i128 = 20;
+
+/// 10^exp trong i128, None nếu overflow
+pub fn checked_pow10(exp: u32) -> Option<i128> {
+    10i128.checked_pow(exp)
+}
+
+/// Đổi basis points sang đơn vị growth scaled
+pub fn bps_to_scaled(bps: i64) -> i128 {
+    (bps as i128) * SCALED_PER_BPS
+}
+
+/// Đổi growth scaled về basis points (làm tròn về 0), saturate trong phạm vi i64
+pub fn scaled_to_bps(scaled: i128) -> i64 {
+    clamp_to_i64(scaled / SCALED_PER_BPS)
+}
+
+/// Ép i128 về i64, saturate ở hai đầu
+pub fn clamp_to_i64(value: i128) -> i64 {
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Growth tuyến tính (final - initial) / initial, scaled by `GROWTH_SCALE`
+///
+/// Trả về None nếu initial = 0. Với giá i64 phép tính không thể overflow i128.
+pub fn linear_growth(initial_price: i64, final_price: i64) -> Option<i128> {
+    if initial_price == 0 {
+        return None;
+    }
+    (final_price as i128)
+        .checked_sub(initial_price as i128)?
+        .checked_mul(GROWTH_SCALE)?
+        .checked_div(initial_price as i128)
+}
+
+/// Log return ln(final / initial), scaled by `GROWTH_SCALE`
+///
+/// Chỉ định nghĩa với giá dương, trả về None nếu một trong hai giá <= 0.
+pub fn log_return(initial_price: i64, final_price: i64) -> Option<i128> {
+    if initial_price <= 0 || final_price <= 0 {
+        return None;
+    }
+    let ln_final = ln_scaled(final_price as i128)?;
+    let ln_initial = ln_scaled(initial_price as i128)?;
+    ln_final
+        .checked_sub(ln_initial)?
+        .checked_div(LN_SCALE / GROWTH_SCALE)
+}
+
+/// ln(x) cho số nguyên dương x, kết quả scaled by 10^18
+///
+/// x = 2^k * m với m trong [1, 2), ln(x) = k * ln(2) + ln(m),
+/// ln(m) = 2 * atanh((m - 1) / (m + 1)) tính bằng chuỗi Taylor.
+fn ln_scaled(x: i128) -> Option<i128> {
+    if x <= 0 {
+        return None;
+    }
+    // k = floor(log2(x)), m = x / 2^k scaled by 10^18
+    let k = 127 - x.leading_zeros() as i128;
+    let m = x.checked_mul(LN_SCALE)? >> k;
+
+    // z = (m - 1) / (m + 1), 0 <= z < 1/3
+    let z = (m - LN_SCALE).checked_mul(LN_SCALE)? / (m + LN_SCALE);
+    let z_squared = z.checked_mul(z)? / LN_SCALE;
+
+    let mut term = z;
+    let mut series = 0i128;
+    let mut n = 0;
+    while n < LN_SERIES_TERMS {
+        series = series.checked_add(term / (2 * n + 1))?;
+        term = term.checked_mul(z_squared)? / LN_SCALE;
+        n += 1;
+    }
+
+    k.checked_mul(LN_2)?.checked_add(series.checked_mul(2)?)
+}
+
+/// Normalize giá trị Pyth từ `exponent` sang `target_exponent`
+///
+/// Dùng i128 và checked pow nên không panic với exponent cực lớn:
+/// - Chia cho 10^diff quá lớn cho kết quả 0
+/// - Nhân với 10^diff bị overflow trả về None (trừ khi value = 0)
+pub fn normalize_exponent(value: i64, exponent: i32, target_exponent: i32) -> Option<i64> {
+    let diff = exponent as i64 - target_exponent as i64;
+    if diff == 0 || value == 0 {
+        return Some(value);
+    }
+
+    let normalized = if diff > 0 {
+        let factor = checked_pow10(u32::try_from(diff).ok()?)?;
+        (value as i128).checked_mul(factor)?
+    } else {
+        match u32::try_from(-diff).ok().and_then(checked_pow10) {
+            Some(factor) => (value as i128) / factor,
+            // 10^diff vượt quá i128 thì lớn hơn mọi giá trị i64
+            None => 0,
+        }
+    };
+
+    i64::try_from(normalized).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE_1E8: i64 = 100_000_000;
+
+    #[test]
+    fn linear_growth_basic_moves() {
+        assert_eq!(linear_growth(PRICE_1E8, PRICE_1E8), Some(0));
+        assert_eq!(linear_growth(PRICE_1E8, 2 * PRICE_1E8), Some(GROWTH_SCALE));
+        assert_eq!(linear_growth(PRICE_1E8, PRICE_1E8 / 2), Some(-GROWTH_SCALE / 2));
+        assert_eq!(linear_growth(PRICE_1E8, 0), Some(-GROWTH_SCALE));
+        // 0.01% = 1 bps = 100_000 scaled
+        assert_eq!(linear_growth(PRICE_1E8, PRICE_1E8 + 10_000), Some(SCALED_PER_BPS));
+    }
+
+    #[test]
+    fn linear_growth_resolves_sub_bps_moves() {
+        // 0.0001% không nhìn thấy được ở bps nhưng vẫn phân biệt được ở 10^9
+        let growth = linear_growth(PRICE_1E8, PRICE_1E8 + 100).unwrap();
+        assert_eq!(growth, 1_000);
+        assert_eq!(scaled_to_bps(growth), 0);
+    }
+
+    #[test]
+    fn linear_growth_zero_initial_is_none() {
+        assert_eq!(linear_growth(0, PRICE_1E8), None);
+    }
+
+    #[test]
+    fn linear_growth_extreme_prices_do_not_overflow() {
+        let up = linear_growth(1, i64::MAX).unwrap();
+        assert_eq!(up, (i64::MAX as i128 - 1) * GROWTH_SCALE);
+
+        let down = linear_growth(i64::MAX, 1).unwrap();
+        assert_eq!(down, -GROWTH_SCALE + 1);
+
+        assert!(linear_growth(i64::MAX, i64::MIN).is_some());
+        assert!(linear_growth(i64::MIN, i64::MAX).is_some());
+        assert!(linear_growth(-1, i64::MAX).is_some());
+    }
+
+    #[test]
+    fn scaled_to_bps_saturates() {
+        let up = linear_growth(1, i64::MAX).unwrap();
+        assert_eq!(scaled_to_bps(up), i64::MAX);
+        assert_eq!(scaled_to_bps(-up), i64::MIN);
+        assert_eq!(scaled_to_bps(bps_to_scaled(-2_000)), -2_000);
+    }
+
+    #[test]
+    fn log_return_known_values() {
+        // ln(1) = 0
+        assert_eq!(log_return(PRICE_1E8, PRICE_1E8), Some(0));
+        // ln(2) = 0.693147180...
+        let ln2 = log_return(PRICE_1E8, 2 * PRICE_1E8).unwrap();
+        assert!((ln2 - 693_147_180).abs() <= 1);
+        // ln(10) = 2.302585092...
+        let ln10 = log_return(1, 10).unwrap();
+        assert!((ln10 - 2_302_585_092).abs() <= 1);
+        // ln(1.5) = 0.405465108...
+        let ln15 = log_return(2 * PRICE_1E8, 3 * PRICE_1E8).unwrap();
+        assert!((ln15 - 405_465_108).abs() <= 1);
+    }
+
+    #[test]
+    fn log_return_is_antisymmetric() {
+        let pairs = [(3, 7), (PRICE_1E8, 123_456_789), (1, i64::MAX), (999, 1_000)];
+        for (a, b) in pairs {
+            let up = log_return(a, b).unwrap();
+            let down = log_return(b, a).unwrap();
+            assert!((up + down).abs() <= 1, "ln({b}/{a}) + ln({a}/{b}) = {}", up + down);
+        }
+    }
+
+    #[test]
+    fn log_return_extreme_prices() {
+        // ln(i64::MAX) = 43.668272375...
+        let max = log_return(1, i64::MAX).unwrap();
+        assert!((max - 43_668_272_375).abs() <= 2);
+        assert!(log_return(i64::MAX, i64::MAX - 1).unwrap() <= 0);
+    }
+
+    #[test]
+    fn log_return_is_monotonic() {
+        let mut previous = i128::MIN;
+        for final_price in [1, 2, 10, 99, 100, 101, 1_000_000, PRICE_1E8, i64::MAX / 2, i64::MAX] {
+            let score = log_return(100, final_price).unwrap();
+            assert!(score > previous);
+            previous = score;
+        }
+    }
+
+    #[test]
+    fn log_return_rejects_non_positive_prices() {
+        assert_eq!(log_return(0, PRICE_1E8), None);
+        assert_eq!(log_return(PRICE_1E8, 0), None);
+        assert_eq!(log_return(-PRICE_1E8, PRICE_1E8), None);
+        assert_eq!(log_return(PRICE_1E8, i64::MIN), None);
+    }
+
+    #[test]
+    fn normalize_exponent_common_cases() {
+        assert_eq!(normalize_exponent(12_345, -8, -8), Some(12_345));
+        assert_eq!(normalize_exponent(12_345, -5, -8), Some(12_345_000));
+        assert_eq!(normalize_exponent(12_345_678, -10, -8), Some(123_456));
+        assert_eq!(normalize_exponent(-12_345_678, -10, -8), Some(-123_456));
+    }
+
+    #[test]
+    fn normalize_exponent_overflow_is_none() {
+        assert_eq!(normalize_exponent(i64::MAX, -7, -8), None);
+        assert_eq!(normalize_exponent(1, 11, -8), None);
+        assert_eq!(normalize_exponent(1, i32::MAX, -8), None);
+        assert_eq!(normalize_exponent(1, i32::MAX, i32::MIN), None);
+    }
+
+    #[test]
+    fn normalize_exponent_huge_negative_exponent_rounds_to_zero() {
+        assert_eq!(normalize_exponent(i64::MAX, -27, -8), Some(0));
+        assert_eq!(normalize_exponent(i64::MAX, i32::MIN, -8), Some(0));
+        assert_eq!(normalize_exponent(i64::MIN, i32::MIN, i32::MAX), Some(0));
+    }
+
+    #[test]
+    fn normalize_exponent_zero_value_never_fails() {
+        assert_eq!(normalize_exponent(0, i32::MAX, i32::MIN), Some(0));
+        assert_eq!(normalize_exponent(0, i32::MIN, i32::MAX), Some(0));
+    }
+
+    #[test]
+    fn checked_pow10_limits() {
+        assert_eq!(checked_pow10(0), Some(1));
+        assert_eq!(checked_pow10(38), Some(10i128.pow(38)));
+        assert_eq!(checked_pow10(39), None);
+        assert_eq!(checked_pow10(u32::MAX), None);
+    }
+}
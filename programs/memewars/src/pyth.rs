@@ -1,114 +1,217 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::account_info::AccountInfo;
+use crate::constants::pyth::{PYTH_PROGRAM_ID, SETTLEMENT_PRICE_TOLERANCE_SECONDS};
 use crate::instructions::MemeWarsError;
+use crate::math;
 
-/// Đọc giá từ Pyth price feed account
-/// 
-/// Pyth price feeds có nhiều format, nhưng chúng ta sẽ đọc trực tiếp từ account data
-/// Format cơ bản: price được lưu ở offset khác nhau tùy version
-pub fn get_pyth_price(price_feed: &AccountInfo) -> Result<i64> {
-    let data = price_feed.try_borrow_data()?;
+/// Mọi giá được normalize về 10^8
+const TARGET_EXPONENT: i32 = -8;
+
+/// Anchor discriminator của `PriceUpdateV2`: sha256("account:PriceUpdateV2")[..8]
+const PRICE_UPDATE_V2_DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+
+/// `VerificationLevel::Full` (borsh enum tag): đã verify đủ chữ ký Wormhole guardian
+const VERIFICATION_LEVEL_FULL: u8 = 1;
+
+/// Pyth feed ID (32 bytes), giống nhau trên mọi chain
+pub type FeedId = [u8; 32];
+
+/// Giá đọc từ Pyth, đã normalize về 10^8
+pub struct PythPrice {
+    /// Feed ID của giá
+    pub feed_id: FeedId,
     
-    // Kiểm tra account có data không
-    require!(data.len() >= 64, MemeWarsError::InvalidPriceFeed);
+    /// Giá (scaled by 10^8)
+    pub price: i64,
     
-    // Validate magic number (Pyth price feed có magic "PTH" = 0x50395448)
-    // Note: We read it but don't validate strictly to support different Pyth versions
-    let _magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    /// Confidence interval (scaled by 10^8), 0 nếu feed không có
+    pub conf: u64,
     
-    // Có thể là Pyth v1 hoặc v2 format
-    // V1: magic = 0x50395448
-    // V2: có thể khác format
-    // Để đơn giản, chúng ta sẽ đọc price ở offset cố định
+    /// EMA price (scaled by 10^8), None nếu feed không có
+    pub ema: Option<i64>,
     
-    // Try to read price at common offsets
-    // Pyth v1: price ở offset 16, exponent ở offset 8
-    let price_offset = 16;
-    let exponent_offset = 8;
+    /// Thời điểm giá được publish (unix timestamp)
+    pub publish_time: i64,
+}
+
+/// Đọc giá từ Pyth price feed account
+pub fn get_pyth_price(price_feed: &AccountInfo) -> Result<i64> {
+    Ok(get_pyth_price_with_confidence(price_feed)?.price)
+}
+
+/// Đọc giá hiện tại và confidence interval từ Pyth price feed account
+/// Giá phải mới (được post trong ~60 giây gần nhất)
+pub fn get_pyth_price_with_confidence(price_feed: &AccountInfo) -> Result<PythPrice> {
+    let (price, posted_slot) = read_price_feed(price_feed)?;
     
-    if data.len() < price_offset + 8 {
-        return Err(MemeWarsError::InvalidPriceFeed.into());
-    }
+    // Check staleness (max 60 seconds = ~150 slots)
+    let clock = Clock::get()?;
+    let slot_diff = clock.slot.saturating_sub(posted_slot);
+    require!(slot_diff <= 150, MemeWarsError::StalePriceFeed);
     
-    // Read price (i64, little endian)
-    let price = i64::from_le_bytes([
-        data[price_offset],
-        data[price_offset + 1],
-        data[price_offset + 2],
-        data[price_offset + 3],
-        data[price_offset + 4],
-        data[price_offset + 5],
-        data[price_offset + 6],
-        data[price_offset + 7],
-    ]);
+    Ok(price)
+}
+
+/// Feed ID của Pyth price feed account, dùng để gắn battle với feed
+pub fn get_feed_id(price_feed: &AccountInfo) -> Result<FeedId> {
+    Ok(read_price_feed(price_feed)?.0.feed_id)
+}
+
+/// Đọc giá dùng để settle từ một Pyth price update của `feed_id`
+/// 
+/// `price_update` có thể là chính price feed của battle hoặc bất kỳ `PriceUpdateV2`
+/// nào được post lên Pyth receiver sau đó (ví dụ update lịch sử tại end_time lấy từ
+/// Hermes), nên propose/dispute không phụ thuộc vào việc feed còn giữ giá tại end_time.
+/// publish_time phải nằm trong ±SETTLEMENT_PRICE_TOLERANCE_SECONDS quanh end_time.
+/// 
+/// Không kiểm tra staleness theo slot: giá settle là giá tại end_time, không phải
+/// giá hiện tại, nên người đề xuất không thể chọn thời điểm chụp giá có lợi.
+pub fn get_settlement_price(
+    price_update: &AccountInfo,
+    feed_id: &FeedId,
+    end_time: i64,
+) -> Result<PythPrice> {
+    let (price, _) = read_price_feed(price_update)?;
+    require!(price.feed_id == *feed_id, MemeWarsError::InvalidPriceFeed);
+    require!(
+        price.publish_time.abs_diff(end_time) <= SETTLEMENT_PRICE_TOLERANCE_SECONDS,
+        MemeWarsError::SettlementPriceOutOfWindow
+    );
+    Ok(price)
+}
+
+/// Kiểm tra account là Pyth price update của `feed_id` (dùng trong account constraint)
+pub fn is_price_update_for(price_update: &AccountInfo, feed_id: &FeedId) -> bool {
+    matches!(read_price_feed(price_update), Ok((price, _)) if price.feed_id == *feed_id)
+}
+
+/// Đọc giá từ Pyth price feed account, trả về giá và slot được post
+/// 
+/// Account phải thuộc Pyth receiver program, nếu không bất kỳ ai cũng có thể
+/// tạo account giả cùng layout.
+fn read_price_feed(price_feed: &AccountInfo) -> Result<(PythPrice, u64)> {
+    require_keys_eq!(
+        *price_feed.owner,
+        PYTH_PROGRAM_ID.parse::<Pubkey>().unwrap(),
+        MemeWarsError::InvalidPriceFeed
+    );
+    let data = price_feed.try_borrow_data()?;
+    parse_price_update(&data)
+}
+
+/// Parse `PriceUpdateV2` của Pyth receiver
+/// 
+/// Layout: discriminator (8) | write_authority (32) | verification_level (1 hoặc 2) |
+/// feed_id (32) | price (i64) | conf (u64) | exponent (i32) | publish_time (i64) |
+/// prev_publish_time (i64) | ema_price (i64) | ema_conf (u64) | posted_slot (u64)
+fn parse_price_update(data: &[u8]) -> Result<(PythPrice, u64)> {
+    require!(
+        data.get(..8) == Some(&PRICE_UPDATE_V2_DISCRIMINATOR[..]),
+        MemeWarsError::InvalidPriceFeed
+    );
     
-    // Read exponent (i32, little endian)
-    let exponent = i32::from_le_bytes([
-        data[exponent_offset],
-        data[exponent_offset + 1],
-        data[exponent_offset + 2],
-        data[exponent_offset + 3],
-    ]);
+    // Chỉ chấp nhận update đã verify đầy đủ (Partial là enum tag 0 kèm số chữ ký)
+    require!(
+        data.get(40) == Some(&VERIFICATION_LEVEL_FULL),
+        MemeWarsError::InvalidPriceFeed
+    );
+    let message = data.get(41..).ok_or(MemeWarsError::InvalidPriceFeed)?;
+    require!(message.len() >= 92, MemeWarsError::InvalidPriceFeed);
     
-    // Read confidence (u64, offset 24)
-    let conf_offset = 24;
-    if data.len() >= conf_offset + 8 {
-        let conf = u64::from_le_bytes([
-            data[conf_offset],
-            data[conf_offset + 1],
-            data[conf_offset + 2],
-            data[conf_offset + 3],
-            data[conf_offset + 4],
-            data[conf_offset + 5],
-            data[conf_offset + 6],
-            data[conf_offset + 7],
-        ]);
-        
-        // Validate confidence (max 5% of price)
-        if price != 0 {
-            let max_conf = (price.abs() as u64)
-                .checked_mul(500)
-                .ok_or(MemeWarsError::Overflow)?
-                .checked_div(10_000)
-                .ok_or(MemeWarsError::Overflow)?;
-            
-            require!(conf <= max_conf, MemeWarsError::LowPriceConfidence);
-        }
-    }
+    let read_i64 = |offset: usize| i64::from_le_bytes(message[offset..offset + 8].try_into().unwrap());
+    let read_u64 = |offset: usize| u64::from_le_bytes(message[offset..offset + 8].try_into().unwrap());
+    
+    let mut feed_id = [0u8; 32];
+    feed_id.copy_from_slice(&message[..32]);
+    let price = read_i64(32);
+    let conf = read_u64(40);
+    let exponent = i32::from_le_bytes(message[48..52].try_into().unwrap());
+    let publish_time = read_i64(52);
+    let ema = read_i64(68);
+    let posted_slot = read_u64(84);
     
-    // Read publish slot để check staleness (offset 32)
-    let slot_offset = 32;
-    if data.len() >= slot_offset + 8 {
-        let pub_slot = u64::from_le_bytes([
-            data[slot_offset],
-            data[slot_offset + 1],
-            data[slot_offset + 2],
-            data[slot_offset + 3],
-            data[slot_offset + 4],
-            data[slot_offset + 5],
-            data[slot_offset + 6],
-            data[slot_offset + 7],
-        ]);
+    // Validate confidence (max 5% of price)
+    if price != 0 {
+        let max_conf = price.unsigned_abs()
+            .checked_mul(500)
+            .ok_or(MemeWarsError::Overflow)?
+            .checked_div(10_000)
+            .ok_or(MemeWarsError::Overflow)?;
         
-        // Check staleness (max 60 seconds = ~150 slots)
-        let clock = Clock::get()?;
-        let slot_diff = clock.slot.saturating_sub(pub_slot);
-        require!(slot_diff <= 150, MemeWarsError::StalePriceFeed);
+        require!(conf <= max_conf, MemeWarsError::LowPriceConfidence);
     }
     
-    // Normalize price to 10^8 (để dễ so sánh)
-    let target_exponent = -8i32;
-    let normalized_price = if exponent > target_exponent {
-        // Need to divide
-        let diff = (exponent - target_exponent) as u32;
-        price.checked_div(10i64.pow(diff)).ok_or(MemeWarsError::Overflow)?
-    } else if exponent < target_exponent {
-        // Need to multiply
-        let diff = (target_exponent - exponent) as u32;
-        price.checked_mul(10i64.pow(diff)).ok_or(MemeWarsError::Overflow)?
+    // Normalize price và confidence về 10^8 (để dễ so sánh)
+    // Dùng i128 checked math, không panic với exponent bất thường
+    let normalized_price = math::normalize_exponent(price, exponent, TARGET_EXPONENT)
+        .ok_or(MemeWarsError::Overflow)?;
+    let normalized_conf = math::normalize_exponent(
+        i64::try_from(conf).map_err(|_| MemeWarsError::Overflow)?,
+        exponent,
+        TARGET_EXPONENT,
+    )
+    .ok_or(MemeWarsError::Overflow)? as u64;
+    let normalized_ema = if ema == 0 {
+        None
     } else {
-        price
+        Some(
+            math::normalize_exponent(ema, exponent, TARGET_EXPONENT)
+                .ok_or(MemeWarsError::Overflow)?,
+        )
     };
     
-    Ok(normalized_price)
+    Ok((
+        PythPrice {
+            feed_id,
+            price: normalized_price,
+            conf: normalized_conf,
+            ema: normalized_ema,
+            publish_time,
+        },
+        posted_slot,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn price_update(verification_level: &[u8], price: i64, exponent: i32, publish_time: i64) -> Vec<u8> {
+        let mut data = PRICE_UPDATE_V2_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(verification_level);
+        data.extend_from_slice(&[7u8; 32]);
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&exponent.to_le_bytes());
+        data.extend_from_slice(&publish_time.to_le_bytes());
+        data.extend_from_slice(&(publish_time - 1).to_le_bytes());
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&42u64.to_le_bytes());
+        data
+    }
+    
+    #[test]
+    fn parses_fully_verified_price_update() {
+        let (price, posted_slot) =
+            parse_price_update(&price_update(&[VERIFICATION_LEVEL_FULL], 12_345, -5, 1_000)).unwrap();
+        assert_eq!(price.feed_id, [7u8; 32]);
+        assert_eq!(price.price, 12_345_000);
+        assert_eq!(price.ema, Some(12_345_000));
+        assert_eq!(price.publish_time, 1_000);
+        assert_eq!(posted_slot, 42);
+    }
+    
+    #[test]
+    fn rejects_partially_verified_or_foreign_accounts() {
+        assert!(parse_price_update(&price_update(&[0, 5], 12_345, -5, 1_000)).is_err());
+        
+        let mut foreign = price_update(&[VERIFICATION_LEVEL_FULL], 12_345, -5, 1_000);
+        foreign[0] ^= 1;
+        assert!(parse_price_update(&foreign).is_err());
+        
+        let truncated = price_update(&[VERIFICATION_LEVEL_FULL], 12_345, -5, 1_000);
+        assert!(parse_price_update(&truncated[..100]).is_err());
+    }
 }
@@ -1,8 +1,78 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::pubkey::Pubkey;
+use crate::constants::{
+    battle_kind, brackets::MAX_BRACKETS, cancel_reason, fees, keepers::MAX_KEEPERS,
+    review_reason, scoring, team, time,
+};
+use crate::instructions::MemeWarsError;
+use crate::math;
+
+/// Vòng đời của một battle
+/// 
+/// Mọi thay đổi phase đi qua `BattlePhase::can_transition_to`, mỗi instruction
+/// khai báo danh sách phase mà nó chấp nhận.
+/// 
+/// ```text
+/// Scheduled -> Open -> Locked -> AwaitingSettlement -> Proposed -> Settled
+///                 \________________^        \_______________________^
+///                                   AwaitingSettlement | Proposed -> NeedsReview -> Proposed
+/// Scheduled | Open | Locked | AwaitingSettlement | Proposed | NeedsReview -> Cancelled
+/// ```
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BattlePhase {
+    /// Đã tạo nhưng chưa đến start_time
+    #[default]
+    Scheduled,
+    
+    /// Đang diễn ra, nhận deposit
+    Open,
+    
+    /// Gần end_time, không nhận deposit và không cho rút sớm
+    Locked,
+    
+    /// Đã qua end_time, chờ settle
+    AwaitingSettlement,
+    
+    /// Đã có kết quả đề xuất, đang trong dispute window
+    Proposed,
+    
+    /// Circuit breaker bị kích hoạt, chờ admin quyết định kết quả
+    NeedsReview,
+    
+    /// Đã settle, user có thể claim
+    Settled,
+    
+    /// Bị hủy, user rút full principal
+    Cancelled,
+}
+
+impl BattlePhase {
+    /// Hàm chuyển phase duy nhất: kiểm tra một bước chuyển có hợp lệ không
+    pub fn can_transition_to(self, next: BattlePhase) -> bool {
+        use BattlePhase::*;
+        matches!(
+            (self, next),
+            (Scheduled, Open)
+                | (Open, Locked)
+                | (Open, AwaitingSettlement)
+                | (Locked, AwaitingSettlement)
+                | (AwaitingSettlement, Proposed)
+                | (AwaitingSettlement, NeedsReview)
+                | (AwaitingSettlement, Settled)
+                | (Proposed, NeedsReview)
+                | (Proposed, Settled)
+                | (NeedsReview, Proposed)
+                | (
+                    Scheduled | Open | Locked | AwaitingSettlement | Proposed | NeedsReview,
+                    Cancelled
+                )
+        )
+    }
+}
 
 /// BattleState: Lưu thông tin về một cuộc chiến giữa 2 token
 #[account]
+#[derive(Default)]
 pub struct BattleState {
     /// ID duy nhất của cuộc chiến
     pub battle_id: u64,
@@ -22,10 +92,10 @@ pub struct BattleState {
     /// Pyth price feed ID cho token B
     pub price_feed_b: Pubkey,
     
-    /// Giá ban đầu của token A (scaled by 10^8)
+    /// Giá ban đầu của token A (scaled by 10^8), 0 cho tới khi battle bắt đầu
     pub initial_price_a: i64,
     
-    /// Giá ban đầu của token B (scaled by 10^8)
+    /// Giá ban đầu của token B (scaled by 10^8), 0 cho tới khi battle bắt đầu
     pub initial_price_b: i64,
     
     /// Giá cuối cùng của token A (scaled by 10^8)
@@ -46,8 +116,8 @@ pub struct BattleState {
     /// Tổng tài sản đã stake của phe B (lamports)
     pub total_staked_b: u64,
     
-    /// Trạng thái cuộc chiến: 0 = Active, 1 = Settled, 2 = Cancelled
-    pub status: u8,
+    /// Phase hiện tại đã lưu (xem `effective_phase` cho các bước chuyển theo thời gian)
+    pub phase: BattlePhase,
     
     /// Phe thắng: 0 = None, 1 = Team A, 2 = Team B
     pub winner: u8,
@@ -64,9 +134,122 @@ pub struct BattleState {
     /// Tổng yield cho team thắng (sau khi trừ protocol fee)
     pub winner_yield: u64,
     
-    /// Protocol fee đã thu (5% của total yield)
+    /// Protocol fee đã thu (`protocol_fee_bps` của total yield)
     pub protocol_fee_collected: u64,
     
+    /// Loại cuộc chiến: 0 = Head-to-head, 1 = Bracket
+    pub kind: u8,
+    
+    /// Số bracket (chỉ dùng cho bracket battle, 0 với head-to-head)
+    pub bracket_count: u8,
+    
+    /// Ranh giới giữa các bracket theo growth bps (tăng dần)
+    /// Bracket i (1-based) chứa growth trong [bounds[i-2], bounds[i-1])
+    pub bracket_bounds: [i64; MAX_BRACKETS - 1],
+    
+    /// Tổng tài sản đã stake của từng bracket (lamports)
+    pub bracket_staked: [u64; MAX_BRACKETS],
+    
+    /// Tổng stake theo thời gian của Team A (lamport-seconds, tính tới end_time)
+    pub stake_seconds_a: u128,
+    
+    /// Tổng stake theo thời gian của Team B (lamport-seconds, tính tới end_time)
+    pub stake_seconds_b: u128,
+    
+    /// Tổng stake theo thời gian của từng bracket (lamport-seconds, tính tới end_time)
+    pub bracket_stake_seconds: [u128; MAX_BRACKETS],
+    
+    /// Handicap (bps) cộng vào growth của token B trước khi so sánh
+    /// Dương = có lợi cho B, âm = có lợi cho A, 0 = không handicap
+    pub handicap_bps: i64,
+    
+    /// Draw band (bps): chênh lệch growth <= giá trị này thì hòa
+    pub tie_tolerance_bps: u64,
+    
+    /// Hòa nếu khoảng growth theo Pyth confidence của 2 token chồng lên nhau
+    pub confidence_ties: bool,
+    
+    /// Cách tính điểm growth: 0 = Linear, 1 = Log return
+    pub scoring_mode: u8,
+    
+    /// Cho phép deposit trước start_time (pre-season)
+    pub pre_season_deposits: bool,
+    
+    /// Thời gian khóa trước end_time (giây), 0 = không khóa
+    /// Trong lock window không nhận deposit và không cho rút sớm
+    pub lock_period_seconds: i64,
+    
+    /// Confidence interval của giá cuối token A (scaled by 10^8)
+    pub final_conf_a: u64,
+    
+    /// Confidence interval của giá cuối token B (scaled by 10^8)
+    pub final_conf_b: u64,
+    
+    /// Lý do hủy battle (xem `constants::cancel_reason`), 0 = chưa hủy
+    pub cancel_reason: u8,
+    
+    /// Người đề xuất kết quả hiện tại (bất kỳ ai sau end_time, hoặc challenger)
+    pub settled_by: Pubkey,
+    
+    /// Bounty đã trả cho keeper (lamports), trích từ protocol fee
+    pub keeper_bounty: u64,
+    
+    /// Hết hạn dispute kết quả đang đề xuất
+    pub dispute_deadline: i64,
+    
+    /// Bond của challenger hiện tại (lamports, giữ trong battle PDA)
+    pub dispute_bond: u64,
+    
+    /// Số lần kết quả bị dispute thành công
+    pub dispute_count: u8,
+    
+    /// Stake tối thiểu (lamports) của mỗi phe để battle được settle
+    /// Dưới ngưỡng thì battle tự hủy khi settle và hoàn lại tiền
+    pub min_team_stake: u64,
+    
+    /// Circuit breaker: |growth| tối đa (bps) của mỗi token, 0 = tắt
+    pub max_growth_bps: u64,
+    
+    /// Circuit breaker: độ lệch tối đa (bps) giữa giá cuối và EMA price, 0 = tắt
+    pub max_ema_divergence_bps: u64,
+    
+    /// Lý do circuit breaker bị kích hoạt (xem `constants::review_reason`)
+    pub review_reason: u8,
+    
+    /// Hết hạn admin xử lý review, sau đó bất kỳ ai cũng có thể hủy battle
+    pub review_deadline: i64,
+    
+    /// KeeperSet phải attest kết quả (Pubkey::default() = settle bằng propose/dispute)
+    pub keeper_set: Pubkey,
+    
+    /// Kết quả do admin force_resolve (oracle hỏng), đang/đã qua timelock
+    pub force_resolved: bool,
+    
+    /// Phần protocol fee đã thực sự chuyển vào treasury (lamports)
+    pub protocol_fee_paid: u64,
+    
+    /// Protocol fee (bps trên yield), snapshot từ ProtocolState khi tạo battle
+    pub protocol_fee_bps: u64,
+    
+    /// Early withdrawal penalty (bps), snapshot từ ProtocolState khi tạo battle
+    pub early_withdrawal_penalty_bps: u64,
+    
+    /// Emergency pause: chặn deposit, rút principal vẫn luôn khả dụng
+    pub paused: bool,
+    
+    /// Tỷ lệ principal thu hồi được khi settle (bps, 10_000 = đủ)
+    /// Thấp hơn 10_000 thì mọi người chơi chịu cùng một haircut
+    pub recovery_bps: u64,
+    
+    /// Lamports insurance fund đã bù vào vault khi settle (trước haircut)
+    pub insurance_payout: u64,
+    
+    /// Pyth feed ID của price_feed_a, price update dùng để settle phải cùng feed
+    pub feed_id_a: [u8; 32],
+    
+    /// Pyth feed ID của price_feed_b (bracket battle để trống)
+    pub feed_id_b: [u8; 32],
+    
     /// Bump seed cho PDA
     pub bump: u8,
 }
@@ -87,63 +270,703 @@ impl BattleState {
         8 + // end_time
         8 + // total_staked_a
         8 + // total_staked_b
-        1 + // status
+        1 + // phase
         1 + // winner
         32 + // vault_a
         32 + // vault_b
         8 + // total_yield_collected
         8 + // winner_yield
         8 + // protocol_fee_collected
+        1 + // kind
+        1 + // bracket_count
+        8 * (MAX_BRACKETS - 1) + // bracket_bounds
+        8 * MAX_BRACKETS + // bracket_staked
+        16 + // stake_seconds_a
+        16 + // stake_seconds_b
+        16 * MAX_BRACKETS + // bracket_stake_seconds
+        8 + // handicap_bps
+        8 + // tie_tolerance_bps
+        1 + // confidence_ties
+        1 + // scoring_mode
+        1 + // pre_season_deposits
+        8 + // lock_period_seconds
+        8 + // final_conf_a
+        8 + // final_conf_b
+        1 + // cancel_reason
+        32 + // settled_by
+        8 + // keeper_bounty
+        8 + // dispute_deadline
+        8 + // dispute_bond
+        1 + // dispute_count
+        8 + // min_team_stake
+        8 + // max_growth_bps
+        8 + // max_ema_divergence_bps
+        1 + // review_reason
+        8 + // review_deadline
+        32 + // keeper_set
+        1 + // force_resolved
+        8 + // protocol_fee_paid
+        8 + // protocol_fee_bps
+        8 + // early_withdrawal_penalty_bps
+        1 + // paused
+        8 + // recovery_bps
+        8 + // insurance_payout
+        32 + // feed_id_a
+        32 + // feed_id_b
         1; // bump
     
-    /// Kiểm tra xem cuộc chiến có đang active không
-    pub fn is_active(&self) -> bool {
-        self.status == 0
+    /// Kiểm tra xem đã đến thời gian kết thúc chưa
+    pub fn is_ended(&self, current_time: i64) -> bool {
+        current_time >= self.end_time
     }
     
-    /// Kiểm tra xem cuộc chiến đã được settle chưa
-    pub fn is_settled(&self) -> bool {
-        self.status == 1
+    /// Hết hạn crank start_battle: start_time + START_WINDOW (không quá end_time)
+    pub fn start_deadline(&self) -> i64 {
+        self.start_time
+            .saturating_add(time::START_WINDOW)
+            .min(self.end_time)
     }
     
-    /// Kiểm tra xem đã đến thời gian kết thúc chưa
-    pub fn is_ended(&self, current_time: i64) -> bool {
-        current_time >= self.end_time
+    /// Hết hạn settle: end_time + SETTLEMENT_GRACE_PERIOD
+    pub fn settlement_deadline(&self) -> i64 {
+        self.end_time.saturating_add(time::SETTLEMENT_GRACE_PERIOD)
+    }
+    
+    /// Hết hạn force_resolve: settlement_deadline + FORCE_RESOLVE_WINDOW
+    /// Sau thời điểm này bất kỳ ai cũng có thể hủy battle chưa settle
+    pub fn force_resolve_deadline(&self) -> i64 {
+        self.settlement_deadline()
+            .saturating_add(time::FORCE_RESOLVE_WINDOW)
+    }
+    
+    /// Admin chỉ được force_resolve trong [settlement_deadline, force_resolve_deadline)
+    pub fn can_force_resolve(&self, current_time: i64) -> bool {
+        current_time >= self.settlement_deadline() && current_time < self.force_resolve_deadline()
+    }
+    
+    /// Thời điểm bắt đầu lock window
+    pub fn lock_time(&self) -> i64 {
+        self.end_time.saturating_sub(self.lock_period_seconds)
+    }
+    
+    /// Kiểm tra xem đã vào lock window chưa
+    pub fn is_locked(&self, current_time: i64) -> bool {
+        current_time >= self.lock_time()
+    }
+    
+    /// Phase thực tế tại thời điểm `current_time`
+    /// Các bước chuyển theo thời gian (hết giờ -> chờ settle) chưa được lưu vẫn được tính vào
+    pub fn effective_phase(&self, current_time: i64) -> BattlePhase {
+        match self.phase {
+            BattlePhase::Open | BattlePhase::Locked if self.is_ended(current_time) => {
+                BattlePhase::AwaitingSettlement
+            }
+            BattlePhase::Open if self.lock_period_seconds > 0 && self.is_locked(current_time) => {
+                BattlePhase::Locked
+            }
+            phase => phase,
+        }
+    }
+    
+    /// Chuyển sang phase mới, chỉ chấp nhận các bước chuyển hợp lệ
+    pub fn transition(&mut self, next: BattlePhase) -> Result<()> {
+        require!(
+            self.phase.can_transition_to(next),
+            MemeWarsError::IllegalPhaseTransition
+        );
+        self.phase = next;
+        Ok(())
+    }
+    
+    /// Lưu các bước chuyển theo thời gian vào `phase`
+    pub fn sync_phase(&mut self, current_time: i64) -> Result<()> {
+        let phase = self.effective_phase(current_time);
+        if phase != self.phase {
+            self.transition(phase)?;
+        }
+        Ok(())
+    }
+    
+    /// Kiểm tra phase hiện tại nằm trong danh sách phase mà instruction chấp nhận
+    pub fn require_phase(&self, accepted: &[BattlePhase], current_time: i64) -> Result<()> {
+        let phase = self.effective_phase(current_time);
+        if !accepted.contains(&phase) {
+            msg!("Battle {} is in phase {:?}, expected one of {:?}", self.battle_id, phase, accepted);
+            return err!(MemeWarsError::InvalidBattlePhase);
+        }
+        Ok(())
     }
     
     /// Tính % tăng trưởng của một token
-    /// Returns basis points (100% = 10000)
+    /// Returns basis points (100% = 10000), saturate trong phạm vi i64
     pub fn calculate_growth_bps(initial_price: i64, final_price: i64) -> i64 {
-        if initial_price == 0 {
-            return 0;
-        }
-        // (final - initial) / initial * 10000
-        ((final_price - initial_price) * 10_000) / initial_price
+        math::linear_growth(initial_price, final_price)
+            .map(math::scaled_to_bps)
+            .unwrap_or(0)
+    }
+    
+    /// Điểm tăng trưởng dùng để xác định winner, scaled by `math::GROWTH_SCALE`
+    /// - Linear: (final - initial) / initial
+    /// - Log return: ln(final / initial), giá cuối <= 0 được coi như đơn vị giá nhỏ nhất
+    pub fn growth_score(&self, initial_price: i64, final_price: i64) -> i128 {
+        let score = if self.scoring_mode == scoring::LOG_RETURN {
+            math::log_return(initial_price, final_price.max(1))
+        } else {
+            math::linear_growth(initial_price, final_price)
+        };
+        score.unwrap_or(0)
     }
     
     /// Xác định winner dựa trên % tăng trưởng
+    /// 
+    /// `record_final_prices` (propose/dispute) ghi final prices rồi gọi hàm này, nên mọi logic so sánh
+    /// (handicap, draw band, confidence) chỉ nằm ở đây
     pub fn determine_winner(&self) -> u8 {
+        if self.is_bracket() {
+            return match self.final_price_a {
+                Some(final_a) => {
+                    let score = self.growth_score(self.initial_price_a, final_a);
+                    self.resolve_bracket_winner(score)
+                }
+                None => team::NONE,
+            };
+        }
+        
         match (self.final_price_a, self.final_price_b) {
             (Some(final_a), Some(final_b)) => {
-                let growth_a = Self::calculate_growth_bps(self.initial_price_a, final_a);
-                let growth_b = Self::calculate_growth_bps(self.initial_price_b, final_b);
+                let score_a = self.growth_score(self.initial_price_a, final_a);
+                let score_b = self.handicapped_score_b(
+                    self.growth_score(self.initial_price_b, final_b),
+                );
+                let tie_band = math::bps_to_scaled(self.tie_tolerance_bps as i64);
                 
-                if growth_a > growth_b {
-                    crate::constants::team::TEAM_A
-                } else if growth_b > growth_a {
-                    crate::constants::team::TEAM_B
+                if score_a.abs_diff(score_b) <= tie_band as u128 {
+                    // Tie (trong draw band) - return to both sides proportionally
+                    team::NONE
+                } else if self.confidence_ties && self.confidence_ranges_overlap(final_a, final_b) {
+                    // Tie - kết quả nằm trong sai số của oracle
+                    team::NONE
+                } else if score_a > score_b {
+                    team::TEAM_A
                 } else {
-                    // Tie - return to both sides proportionally
-                    crate::constants::team::NONE
+                    team::TEAM_B
+                }
+            }
+            _ => team::NONE,
+        }
+    }
+    
+    /// Kiểm tra khoảng growth [final - conf, final + conf] của 2 token có chồng lên nhau không
+    fn confidence_ranges_overlap(&self, final_a: i64, final_b: i64) -> bool {
+        let (low_a, high_a) =
+            self.score_range(self.initial_price_a, final_a, self.final_conf_a);
+        let (low_b, high_b) =
+            self.score_range(self.initial_price_b, final_b, self.final_conf_b);
+        let low_b = self.handicapped_score_b(low_b);
+        let high_b = self.handicapped_score_b(high_b);
+        
+        low_a <= high_b && low_b <= high_a
+    }
+    
+    /// Khoảng điểm growth ứng với giá cuối ± confidence
+    fn score_range(&self, initial_price: i64, final_price: i64, conf: u64) -> (i128, i128) {
+        let conf = i64::try_from(conf).unwrap_or(i64::MAX);
+        (
+            self.growth_score(initial_price, final_price.saturating_sub(conf)),
+            self.growth_score(initial_price, final_price.saturating_add(conf)),
+        )
+    }
+    
+    /// Điểm growth của token B sau khi cộng handicap
+    pub fn handicapped_score_b(&self, score_b: i128) -> i128 {
+        score_b.saturating_add(math::bps_to_scaled(self.handicap_bps))
+    }
+    
+    /// Tóm tắt thông tin battle cho client
+    pub fn summary(&self, current_time: i64) -> BattleSummary {
+        let growth_a = self
+            .final_price_a
+            .map(|final_a| Self::calculate_growth_bps(self.initial_price_a, final_a));
+        let growth_b = self
+            .final_price_b
+            .map(|final_b| Self::calculate_growth_bps(self.initial_price_b, final_b));
+        
+        BattleSummary {
+            battle_id: self.battle_id,
+            kind: self.kind,
+            phase: self.effective_phase(current_time),
+            winner: self.winner,
+            token_a: self.token_a,
+            token_b: self.token_b,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            pre_season_deposits: self.pre_season_deposits,
+            lock_time: self.lock_time(),
+            total_staked: self.total_staked(),
+            total_staked_a: self.team_staked(team::TEAM_A),
+            total_staked_b: self.team_staked(team::TEAM_B),
+            handicap_bps: self.handicap_bps,
+            tie_tolerance_bps: self.tie_tolerance_bps,
+            confidence_ties: self.confidence_ties,
+            scoring_mode: self.scoring_mode,
+            growth_a_bps: growth_a,
+            growth_b_bps: growth_b,
+            winner_yield: self.winner_yield,
+            dispute_deadline: self.dispute_deadline,
+            settlement_deadline: self.settlement_deadline(),
+            force_resolve_deadline: self.force_resolve_deadline(),
+            review_reason: self.review_reason,
+            review_deadline: self.review_deadline,
+            keeper_set: self.keeper_set,
+            paused: self.paused,
+            recovery_bps: self.recovery_bps,
+            insurance_payout: self.insurance_payout,
+            force_resolved: self.force_resolved,
+            cancel_reason: self.cancel_reason,
+        }
+    }
+    
+    /// Kiểm tra xem đây có phải bracket battle không
+    pub fn is_bracket(&self) -> bool {
+        self.kind == battle_kind::BRACKET
+    }
+    
+    /// Số phe trong cuộc chiến (team hợp lệ là 1..=team_count)
+    pub fn team_count(&self) -> u8 {
+        if self.is_bracket() {
+            self.bracket_count
+        } else {
+            2
+        }
+    }
+    
+    /// Kiểm tra team có thuộc cuộc chiến này không
+    pub fn is_valid_team(&self, team: u8) -> bool {
+        team != team::NONE && team <= self.team_count()
+    }
+    
+    /// Tổng stake của một phe
+    pub fn team_staked(&self, team: u8) -> u64 {
+        if !self.is_valid_team(team) {
+            return 0;
+        }
+        if self.is_bracket() {
+            self.bracket_staked[(team - 1) as usize]
+        } else if team == team::TEAM_A {
+            self.total_staked_a
+        } else {
+            self.total_staked_b
+        }
+    }
+    
+    /// Tổng stake của tất cả các phe
+    pub fn total_staked(&self) -> u64 {
+        (1..=self.team_count())
+            .fold(0u64, |acc, team| acc.saturating_add(self.team_staked(team)))
+    }
+    
+    fn team_staked_mut(&mut self, team: u8) -> Result<&mut u64> {
+        require!(self.is_valid_team(team), MemeWarsError::InvalidTeam);
+        Ok(if self.is_bracket() {
+            &mut self.bracket_staked[(team - 1) as usize]
+        } else if team == team::TEAM_A {
+            &mut self.total_staked_a
+        } else {
+            &mut self.total_staked_b
+        })
+    }
+    
+    /// Cộng thêm stake cho một phe
+    pub fn add_team_stake(&mut self, team: u8, amount: u64) -> Result<()> {
+        let staked = self.team_staked_mut(team)?;
+        *staked = staked.checked_add(amount).ok_or(MemeWarsError::Overflow)?;
+        Ok(())
+    }
+    
+    /// Trừ stake của một phe (khi user rút)
+    pub fn sub_team_stake(&mut self, team: u8, amount: u64) -> Result<()> {
+        let staked = self.team_staked_mut(team)?;
+        *staked = staked.saturating_sub(amount);
+        Ok(())
+    }
+    
+    /// Stake theo thời gian (lamport-seconds) mà `amount` đóng góp nếu giữ tới end_time
+    /// Deposit pre-season chỉ được tính từ start_time
+    pub fn stake_weight(&self, amount: u64, current_time: i64) -> Result<u128> {
+        let remaining = self
+            .end_time
+            .saturating_sub(current_time.max(self.start_time))
+            .max(0) as u128;
+        (amount as u128)
+            .checked_mul(remaining)
+            .ok_or(MemeWarsError::Overflow.into())
+    }
+    
+    /// Stake theo thời gian (lamport-seconds) của một phe
+    pub fn team_stake_seconds(&self, team: u8) -> u128 {
+        if !self.is_valid_team(team) {
+            return 0;
+        }
+        if self.is_bracket() {
+            self.bracket_stake_seconds[(team - 1) as usize]
+        } else if team == team::TEAM_A {
+            self.stake_seconds_a
+        } else {
+            self.stake_seconds_b
+        }
+    }
+    
+    /// Tổng stake theo thời gian của tất cả các phe
+    pub fn total_stake_seconds(&self) -> u128 {
+        (1..=self.team_count())
+            .fold(0u128, |acc, team| acc.saturating_add(self.team_stake_seconds(team)))
+    }
+    
+    fn team_stake_seconds_mut(&mut self, team: u8) -> Result<&mut u128> {
+        require!(self.is_valid_team(team), MemeWarsError::InvalidTeam);
+        Ok(if self.is_bracket() {
+            &mut self.bracket_stake_seconds[(team - 1) as usize]
+        } else if team == team::TEAM_A {
+            &mut self.stake_seconds_a
+        } else {
+            &mut self.stake_seconds_b
+        })
+    }
+    
+    /// Cộng thêm stake theo thời gian cho một phe
+    pub fn add_team_stake_seconds(&mut self, team: u8, stake_seconds: u128) -> Result<()> {
+        let total = self.team_stake_seconds_mut(team)?;
+        *total = total.checked_add(stake_seconds).ok_or(MemeWarsError::Overflow)?;
+        Ok(())
+    }
+    
+    /// Trừ stake theo thời gian của một phe (khi user rút)
+    pub fn sub_team_stake_seconds(&mut self, team: u8, stake_seconds: u128) -> Result<()> {
+        let total = self.team_stake_seconds_mut(team)?;
+        *total = total.saturating_sub(stake_seconds);
+        Ok(())
+    }
+    
+    /// Lý do mà bất kỳ ai cũng có thể hủy battle, None nếu chỉ authority được hủy
+    pub fn permissionless_cancel_reason(&self, current_time: i64) -> Option<u8> {
+        match self.effective_phase(current_time) {
+            // Không chụp giá ban đầu kịp thì không bao giờ settle được
+            BattlePhase::Scheduled if current_time >= self.start_deadline() => {
+                Some(cancel_reason::NEVER_STARTED)
+            }
+            // Admin không xử lý circuit breaker kịp
+            BattlePhase::NeedsReview if current_time >= self.review_deadline => {
+                Some(cancel_reason::REVIEW_TIMEOUT)
+            }
+            // Quá hạn settle và admin không force_resolve, tránh tiền bị kẹt vĩnh viễn
+            BattlePhase::AwaitingSettlement if current_time >= self.force_resolve_deadline() => {
+                Some(cancel_reason::SETTLEMENT_TIMEOUT)
+            }
+            _ => None,
+        }
+    }
+    
+    /// Người chơi rút lại toàn bộ tiền không mất penalty:
+    /// battle đã hủy, hoặc kết quả force_resolve đang trong timelock
+    pub fn is_refund_window(&self) -> bool {
+        match self.phase {
+            BattlePhase::Cancelled => true,
+            BattlePhase::Proposed => self.force_resolved,
+            _ => false,
+        }
+    }
+    
+    /// Kiểm tra battle có đủ người tham gia để settle
+    /// - Head-to-head: cả 2 phe đều có stake >= min_team_stake (và > 0)
+    /// - Bracket: ít nhất 2 bracket có stake >= min_team_stake (và > 0)
+    pub fn has_min_participation(&self) -> bool {
+        let threshold = self.min_team_stake.max(1);
+        let qualified = (1..=self.team_count())
+            .filter(|team| self.team_staked(*team) >= threshold)
+            .count();
+        if self.is_bracket() {
+            qualified >= 2
+        } else {
+            qualified == 2
+        }
+    }
+    
+    /// Kiểm tra circuit breaker với giá cuối đã ghi nhận và EMA price tương ứng
+    /// Trả về `review_reason::NONE` nếu kết quả hợp lý
+    pub fn circuit_breaker_reason(&self, ema_a: Option<i64>, ema_b: Option<i64>) -> u8 {
+        let legs = [
+            (self.initial_price_a, self.final_price_a, ema_a),
+            (self.initial_price_b, self.final_price_b, ema_b),
+        ];
+        
+        if self.max_growth_bps > 0 {
+            let too_large = legs.iter().any(|(initial, final_price, _)| {
+                final_price.is_some_and(|final_price| {
+                    Self::calculate_growth_bps(*initial, final_price).unsigned_abs()
+                        > self.max_growth_bps
+                })
+            });
+            if too_large {
+                return review_reason::GROWTH_LIMIT;
+            }
+        }
+        
+        if self.max_ema_divergence_bps > 0 {
+            let diverged = legs.iter().any(|(_, final_price, ema)| match (final_price, ema) {
+                (Some(final_price), Some(ema)) => {
+                    Self::calculate_growth_bps(*ema, *final_price).unsigned_abs()
+                        > self.max_ema_divergence_bps
                 }
+                _ => false,
+            });
+            if diverged {
+                return review_reason::EMA_DIVERGENCE;
             }
-            _ => crate::constants::team::NONE,
+        }
+        
+        review_reason::NONE
+    }
+    
+    /// Hủy battle, lưu lý do
+    pub fn cancel(&mut self, reason: u8) -> Result<()> {
+        self.transition(BattlePhase::Cancelled)?;
+        self.cancel_reason = reason;
+        Ok(())
+    }
+    
+    /// Tìm bracket (1-based) chứa điểm growth (scaled by `math::GROWTH_SCALE`)
+    pub fn bracket_for_score(&self, score: i128) -> u8 {
+        let bound_count = self.bracket_count.saturating_sub(1) as usize;
+        let below = self.bracket_bounds[..bound_count]
+            .iter()
+            .take_while(|bound| score >= math::bps_to_scaled(**bound))
+            .count();
+        (below + 1) as u8
+    }
+    
+    /// Xác định bracket thắng
+    /// Nếu không ai chọn bracket đó thì coi như hòa để yield chia đều cho mọi người
+    pub fn resolve_bracket_winner(&self, score: i128) -> u8 {
+        let bracket = self.bracket_for_score(score);
+        if self.team_staked(bracket) == 0 {
+            team::NONE
+        } else {
+            bracket
+        }
+    }
+    
+    /// Chia yield thực nhận khi settle thành (winner_yield, protocol_fee)
+    /// Fee theo tỷ lệ snapshot khi tạo battle, làm tròn xuống
+    pub fn split_yield(&self, realised_yield: u64) -> (u64, u64) {
+        let protocol_fee = mul_div(
+            realised_yield,
+            self.protocol_fee_bps as u128,
+            fees::BPS_DIVISOR as u128,
+        );
+        (realised_yield.saturating_sub(protocol_fee), protocol_fee)
+    }
+    
+    /// Phần winner_yield thuộc về một phe sau khi settle
+    /// - Winner: toàn bộ winner_yield
+    /// - Tie: chia theo tỷ lệ stake theo thời gian
+    /// - Loser: 0
+    pub fn team_yield(&self, team: u8) -> u64 {
+        if self.winner == team::NONE {
+            let total_stake_seconds = self.total_stake_seconds();
+            if total_stake_seconds == 0 {
+                return 0;
+            }
+            mul_div(self.winner_yield, self.team_stake_seconds(team), total_stake_seconds)
+        } else if self.winner == team {
+            self.winner_yield
+        } else {
+            0
         }
     }
 }
 
+/// ScoringParams: Cấu hình cách xác định winner khi tạo head-to-head battle
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct ScoringParams {
+    /// Handicap (bps) cộng vào growth của token B (0 = không handicap)
+    pub handicap_bps: i64,
+    
+    /// Draw band: chênh lệch growth (bps) trong khoảng này thì hòa
+    pub tie_tolerance_bps: u64,
+    
+    /// Hòa nếu khoảng Pyth confidence của 2 token chồng lên nhau
+    pub confidence_ties: bool,
+    
+    /// Cách tính điểm growth: 0 = Linear, 1 = Log return
+    pub scoring_mode: u8,
+}
+
+/// ScheduleParams: Thời gian bắt đầu của battle
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct ScheduleParams {
+    /// Thời gian bắt đầu (Unix timestamp), <= hiện tại thì bắt đầu ngay
+    /// Battle bắt đầu trong tương lai cần crank `start_battle` để chụp giá ban đầu
+    pub start_time: i64,
+    
+    /// Cho phép deposit trước start_time (pre-season)
+    pub pre_season_deposits: bool,
+    
+    /// Thời gian khóa trước end_time (giây), 0 = không khóa
+    pub lock_period_seconds: i64,
+}
+
+/// SafetyParams: Giới hạn an toàn khi settle
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct SafetyParams {
+    /// Stake tối thiểu (lamports) mỗi phe, dưới ngưỡng thì battle tự hủy khi settle
+    pub min_team_stake: u64,
+    
+    /// |growth| tối đa (bps) của mỗi token, vượt quá thì cần admin xem xét (0 = tắt)
+    pub max_growth_bps: u64,
+    
+    /// Độ lệch tối đa (bps) giữa giá cuối và EMA price, vượt quá thì cần admin xem xét (0 = tắt)
+    pub max_ema_divergence_bps: u64,
+}
+
+/// RoleParams: Phân quyền protocol do admin quản lý
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct RoleParams {
+    /// Được tạo battle (Pubkey::default() = bất kỳ ai)
+    pub battle_creator: Pubkey,
+    
+    /// Keeper/settler: xử lý review và force_resolve của mọi battle
+    pub settler: Pubkey,
+    
+    /// Quản lý treasury: rút protocol fees
+    pub treasury_manager: Pubkey,
+}
+
+/// ConfigParams: Tham số phí của protocol do admin quản lý
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct ConfigParams {
+    /// Protocol fee (bps trên yield), tối đa `fees::MAX_PROTOCOL_FEE_BPS`
+    pub protocol_fee_bps: u64,
+    
+    /// Early withdrawal penalty (bps), tối đa `fees::MAX_EARLY_WITHDRAWAL_PENALTY_BPS`
+    pub early_withdrawal_penalty_bps: u64,
+    
+    /// Phần protocol fee chuyển vào insurance fund (bps), tối đa 10_000
+    pub insurance_fee_share_bps: u64,
+    
+    /// Coverage tối đa insurance fund bù cho mỗi battle (bps trên principal), tối đa 10_000
+    pub max_coverage_bps: u64,
+}
+
+/// CoverageSummary: Mức bảo hiểm principal của một battle qua `get_insurance_coverage`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct CoverageSummary {
+    pub battle_id: u64,
+    
+    /// Principal người chơi đang stake
+    pub principal: u64,
+    
+    /// Số lamports tối đa insurance fund bù cho battle (theo `max_coverage_bps`)
+    pub coverage_limit: u64,
+    
+    /// Số dư hiện tại của insurance fund
+    pub insurance_balance: u64,
+    
+    /// Tỷ lệ principal được bảo hiểm thực tế (bps, 10_000 = toàn bộ)
+    pub coverage_bps: u64,
+}
+
+/// BattleSummary: Dữ liệu trả về cho client qua `get_battle_summary`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct BattleSummary {
+    pub battle_id: u64,
+    
+    /// Loại cuộc chiến: 0 = Head-to-head, 1 = Bracket
+    pub kind: u8,
+    
+    /// Phase hiện tại của battle
+    pub phase: BattlePhase,
+    
+    /// Phe thắng (0 nếu chưa settle hoặc hòa)
+    pub winner: u8,
+    
+    pub token_a: Pubkey,
+    pub token_b: Pubkey,
+    pub start_time: i64,
+    pub end_time: i64,
+    
+    /// Cho phép deposit trước start_time
+    pub pre_season_deposits: bool,
+    
+    /// Thời điểm bắt đầu lock window (= end_time nếu không khóa)
+    pub lock_time: i64,
+    
+    /// Tổng stake của tất cả các phe
+    pub total_staked: u64,
+    
+    /// Stake của team A / bracket 1
+    pub total_staked_a: u64,
+    
+    /// Stake của team B / bracket 2
+    pub total_staked_b: u64,
+    
+    /// Handicap (bps) cộng vào growth của token B
+    pub handicap_bps: i64,
+    
+    /// Draw band (bps)
+    pub tie_tolerance_bps: u64,
+    
+    /// Hòa khi khoảng confidence chồng lên nhau
+    pub confidence_ties: bool,
+    
+    /// Cách tính điểm growth: 0 = Linear, 1 = Log return
+    pub scoring_mode: u8,
+    
+    /// Growth của token A (chưa tính handicap), có sau khi settle
+    pub growth_a_bps: Option<i64>,
+    
+    /// Growth của token B (chưa tính handicap), có sau khi settle
+    pub growth_b_bps: Option<i64>,
+    
+    /// Yield chia cho phe thắng
+    pub winner_yield: u64,
+    
+    /// Hết hạn dispute kết quả đang đề xuất (0 nếu chưa đề xuất)
+    pub dispute_deadline: i64,
+    
+    /// Sau thời điểm này admin có thể force_resolve battle chưa settle
+    pub settlement_deadline: i64,
+    
+    /// Sau thời điểm này bất kỳ ai cũng có thể hủy battle chưa settle
+    pub force_resolve_deadline: i64,
+    
+    /// Lý do circuit breaker bị kích hoạt (xem `constants::review_reason`)
+    pub review_reason: u8,
+    
+    /// Hết hạn admin xử lý review
+    pub review_deadline: i64,
+    
+    /// KeeperSet attest kết quả (Pubkey::default() nếu không dùng)
+    pub keeper_set: Pubkey,
+    
+    /// Battle đang bị pause (không nhận deposit)
+    pub paused: bool,
+    
+    /// Tỷ lệ principal thu hồi được khi settle (bps, 10_000 = đủ)
+    pub recovery_bps: u64,
+    
+    /// Lamports insurance fund đã bù khi settle
+    pub insurance_payout: u64,
+    
+    /// Kết quả do admin force_resolve, finalize được sau `dispute_deadline`
+    pub force_resolved: bool,
+    
+    /// Lý do hủy battle (xem `constants::cancel_reason`)
+    pub cancel_reason: u8,
+}
+
 /// UserState: Lưu thông tin về người chơi trong một cuộc chiến
 #[account]
+#[derive(Default)]
 pub struct UserState {
     /// Người chơi (wallet address)
     pub user: Pubkey,
@@ -151,15 +974,22 @@ pub struct UserState {
     /// ID của cuộc chiến mà người chơi tham gia
     pub battle_id: u64,
     
-    /// Phe mà người chơi chọn: 1 = Team A, 2 = Team B
+    /// Phe mà người chơi chọn: 1 = Team A, 2 = Team B (bracket battle: số thứ tự bracket)
     pub team: u8,
     
     /// Số tiền đã stake (lamports)
     pub amount_staked: u64,
     
+    /// Số shares của vault mà user nắm giữ (bằng số ticket token đã mint)
+    pub shares: u64,
+    
     /// Thời gian stake (Unix timestamp)
     pub stake_time: i64,
     
+    /// Stake theo thời gian (lamport-seconds, tính tới end_time)
+    /// Cộng dồn mỗi lần deposit, dùng để chia yield
+    pub stake_seconds: u128,
+    
     /// Đã claim thưởng chưa: false = chưa, true = rồi
     pub claimed: bool,
     
@@ -176,58 +1006,54 @@ impl UserState {
         8 + // battle_id
         1 + // team
         8 + // amount_staked
+        8 + // shares
         8 + // stake_time
+        16 + // stake_seconds
         1 + // claimed
         8 + // reward_amount
         1; // bump
     
     /// Tính số tiền user nhận được dựa trên kết quả battle
     /// 
-    /// Logic:
-    /// - Winner: principal + (user_stake / total_winner_stake) * winner_yield
+    /// Principal là giá trị hiện tại của shares trong vault (gồm lãi/lỗ từ lending,
+    /// sau haircut khi settle nếu lending trả về ít hơn principal).
+    /// Logic (yield chia theo lamport-seconds, deposit muộn nhận ít hơn):
+    /// - Winner: principal + (user_stake_seconds / team_stake_seconds) * winner_yield
     /// - Loser: principal only
-    /// - Tie: principal + proportional yield
+    /// - Tie: principal + (user_stake_seconds / total_stake_seconds) * winner_yield
     pub fn calculate_claim_amount(
         &self,
         battle: &BattleState,
+        vault: &Vault,
     ) -> u64 {
+        let principal = vault.shares_to_amount(self.shares);
         let is_winner = self.team == battle.winner;
-        let is_tie = battle.winner == crate::constants::team::NONE;
+        let is_tie = battle.winner == team::NONE;
         
         if is_tie {
             // Tie: trả lại principal + phần yield tỷ lệ
-            let total_staked = battle.total_staked_a.saturating_add(battle.total_staked_b);
-            if total_staked == 0 {
-                return self.amount_staked;
+            let total_stake_seconds = battle.total_stake_seconds();
+            if total_stake_seconds == 0 {
+                return principal;
             }
             
-            let user_yield = (battle.winner_yield as u128)
-                .saturating_mul(self.amount_staked as u128)
-                .checked_div(total_staked as u128)
-                .unwrap_or(0) as u64;
+            let user_yield = mul_div(battle.winner_yield, self.stake_seconds, total_stake_seconds);
             
-            self.amount_staked.saturating_add(user_yield)
+            principal.saturating_add(user_yield)
         } else if is_winner {
             // Winner: principal + phần yield tỷ lệ
-            let total_winner_stake = if self.team == crate::constants::team::TEAM_A {
-                battle.total_staked_a
-            } else {
-                battle.total_staked_b
-            };
+            let team_stake_seconds = battle.team_stake_seconds(self.team);
             
-            if total_winner_stake == 0 {
-                return self.amount_staked;
+            if team_stake_seconds == 0 {
+                return principal;
             }
             
-            let user_yield = (battle.winner_yield as u128)
-                .saturating_mul(self.amount_staked as u128)
-                .checked_div(total_winner_stake as u128)
-                .unwrap_or(0) as u64;
+            let user_yield = mul_div(battle.winner_yield, self.stake_seconds, team_stake_seconds);
             
-            self.amount_staked.saturating_add(user_yield)
+            principal.saturating_add(user_yield)
         } else {
             // Loser: chỉ principal
-            self.amount_staked
+            principal
         }
     }
 }
@@ -238,16 +1064,20 @@ impl UserState {
 /// - Team thắng: Nhận vốn gốc + lãi của mình + TOÀN BỘ lãi của team thua
 /// - Team thua: Chỉ nhận vốn gốc (không có lãi, vì lãi đã chuyển cho team thắng)
 #[account]
+#[derive(Default)]
 pub struct Vault {
     /// ID của cuộc chiến
     pub battle_id: u64,
     
-    /// Phe: 1 = Team A, 2 = Team B
+    /// Phe: 1 = Team A, 2 = Team B (bracket battle: số thứ tự bracket)
     pub team: u8,
     
-    /// Tổng số tiền trong vault (lamports) - vốn gốc đã stake
+    /// Giá trị hiện tại của vault (lamports) - vốn gốc đã stake cộng lãi/lỗ từ lending
     pub total_amount: u64,
     
+    /// Tổng số shares đã phát hành (bằng tổng ticket token đang lưu hành)
+    pub total_shares: u64,
+    
     /// Số tiền đã được gửi vào lending protocol (lamports)
     pub lent_amount: u64,
     
@@ -272,6 +1102,7 @@ impl Vault {
         8 + // battle_id
         1 + // team
         8 + // total_amount
+        8 + // total_shares
         8 + // lent_amount
         1 + 32 + // Option<Pubkey> for lending_position
         8 + // msol_balance
@@ -289,18 +1120,77 @@ impl Vault {
         self.total_amount.saturating_sub(self.lent_amount)
     }
     
+    /// Số shares nhận được khi deposit `amount` theo giá trị hiện tại của mỗi share
+    /// Vault rỗng phát hành 1 share / lamport; làm tròn xuống để có lợi cho vault
+    pub fn amount_to_shares(&self, amount: u64) -> Result<u64> {
+        if self.total_shares == 0 || self.total_amount == 0 {
+            return Ok(amount);
+        }
+        let shares = (amount as u128)
+            .checked_mul(self.total_shares as u128)
+            .ok_or(MemeWarsError::Overflow)?
+            / self.total_amount as u128;
+        u64::try_from(shares).map_err(|_| MemeWarsError::Overflow.into())
+    }
+    
+    /// Giá trị (lamports) của `shares` theo giá trị hiện tại của vault, làm tròn xuống
+    pub fn shares_to_amount(&self, shares: u64) -> u64 {
+        if self.total_shares == 0 {
+            return 0;
+        }
+        mul_div(self.total_amount, shares as u128, self.total_shares as u128)
+    }
+    
     /// Tổng số tiền có thể claim (principal + yield nếu là winner)
     pub fn claimable_amount(&self) -> u64 {
         self.total_amount
             .saturating_add(self.yield_collected)
             .saturating_sub(self.claimed_amount)
     }
-}
+    
+    /// Giá trị còn lại khi shares cuối cùng đã được rút (penalty, dư làm tròn)
+    /// 
+    /// Số tiền này không thuộc về ai; nếu giữ lại thì depositor tiếp theo nhận trọn.
+    /// Reset total_amount về 0 và trả về số tiền để chuyển vào treasury.
+    pub fn take_orphaned_amount(&mut self) -> u64 {
+        if self.total_shares > 0 {
+            return 0;
+        }
+        std::mem::take(&mut self.total_amount)
+    }
+    
+    /// Emergency unwind: ghi nhận giá trị vault bằng số lamports thực sự thu hồi được
+    /// 
+    /// Chỉ ghi giảm khi mSOL đã được unstake hết về vault (`msol_balance == 0`); nếu
+    /// chưa, lending position vẫn được giữ và không ghi giảm gì. Sau khi unstake,
+    /// lending position được đóng, phần không thu hồi được là lỗ chia đều theo shares.
+    /// Trả về số lamports bị ghi giảm.
+    pub fn write_down_to(&mut self, recovered: u64) -> u64 {
+        if self.msol_balance > 0 {
+            return 0;
+        }
+        let loss = self.total_amount.saturating_sub(recovered);
+        self.total_amount = self.total_amount.min(recovered);
+        self.lent_amount = 0;
+        self.lending_position = None;
+        self.msol_balance = 0;
+        loss
+    }
+    
+    /// Lamports vượt quá rent-exempt minimum và toàn bộ số tiền còn nợ người chơi
+    /// (kể cả yield đã chia khi settle), có thể chuyển vào treasury làm protocol fee
+    pub fn excess_lamports(&self, lamports: u64, rent_exempt_minimum: u64) -> u64 {
+        lamports
+            .saturating_sub(rent_exempt_minimum)
+            .saturating_sub(self.claimable_amount())
+    }
+}
 
 /// ProtocolState: Lưu thông tin global của protocol
 #[account]
+#[derive(Default)]
 pub struct ProtocolState {
-    /// Authority có quyền admin
+    /// Authority có quyền admin (config, roles), chuyển giao qua propose/accept
     pub authority: Pubkey,
     
     /// Treasury để nhận protocol fees
@@ -315,6 +1205,39 @@ pub struct ProtocolState {
     /// Tổng fees đã thu
     pub total_fees_collected: u64,
     
+    /// Protocol fee (bps) cho battle tạo mới
+    pub protocol_fee_bps: u64,
+    
+    /// Early withdrawal penalty (bps) cho battle tạo mới
+    pub early_withdrawal_penalty_bps: u64,
+    
+    /// Admin mới đang chờ accept (Pubkey::default() = không có)
+    pub pending_authority: Pubkey,
+    
+    /// Role được tạo battle (Pubkey::default() = bất kỳ ai)
+    pub battle_creator: Pubkey,
+    
+    /// Role keeper/settler: xử lý review và force_resolve thay cho battle authority
+    pub settler: Pubkey,
+    
+    /// Role quản lý treasury: rút protocol fees
+    pub treasury_manager: Pubkey,
+    
+    /// Emergency pause toàn protocol: chặn tạo battle và deposit
+    pub paused: bool,
+    
+    /// Insurance fund bù thiếu hụt principal khi settle
+    pub insurance_fund: Pubkey,
+    
+    /// Phần protocol fee chuyển vào insurance fund (bps)
+    pub insurance_fee_share_bps: u64,
+    
+    /// Coverage tối đa cho mỗi battle (bps trên principal)
+    pub max_coverage_bps: u64,
+    
+    /// Số dư insurance fund (lamports có thể dùng để bù)
+    pub insurance_balance: u64,
+    
     /// Bump seed
     pub bump: u8,
 }
@@ -326,5 +1249,1148 @@ impl ProtocolState {
         8 + // total_battles
         8 + // total_tvl
         8 + // total_fees_collected
+        8 + // protocol_fee_bps
+        8 + // early_withdrawal_penalty_bps
+        32 + // pending_authority
+        32 + // battle_creator
+        32 + // settler
+        32 + // treasury_manager
+        1 + // paused
+        32 + // insurance_fund
+        8 + // insurance_fee_share_bps
+        8 + // max_coverage_bps
+        8 + // insurance_balance
+        1; // bump
+    
+    /// Cập nhật các role; settler và treasury manager bắt buộc phải có
+    pub fn set_roles(&mut self, roles: &RoleParams) -> Result<()> {
+        require!(
+            roles.settler != Pubkey::default() && roles.treasury_manager != Pubkey::default(),
+            MemeWarsError::InvalidRole
+        );
+        self.battle_creator = roles.battle_creator;
+        self.settler = roles.settler;
+        self.treasury_manager = roles.treasury_manager;
+        Ok(())
+    }
+    
+    /// Admin luôn được tạo battle; nếu không đặt battle_creator thì ai cũng được
+    pub fn can_create_battles(&self, key: &Pubkey) -> bool {
+        self.battle_creator == Pubkey::default()
+            || *key == self.battle_creator
+            || *key == self.authority
+    }
+    
+    /// Quyền xử lý settlement cần người quyết định (review, force_resolve):
+    /// authority của battle hoặc settler của protocol
+    pub fn can_settle(&self, battle: &BattleState, key: &Pubkey) -> bool {
+        *key == battle.authority || *key == self.settler
+    }
+    
+    /// Cập nhật tham số phí, giới hạn bởi các giá trị tối đa cố định
+    pub fn set_config(&mut self, config: &ConfigParams) -> Result<()> {
+        require!(
+            config.protocol_fee_bps <= fees::MAX_PROTOCOL_FEE_BPS
+                && config.early_withdrawal_penalty_bps <= fees::MAX_EARLY_WITHDRAWAL_PENALTY_BPS
+                && config.insurance_fee_share_bps <= fees::BPS_DIVISOR
+                && config.max_coverage_bps <= fees::BPS_DIVISOR,
+            MemeWarsError::InvalidConfig
+        );
+        self.protocol_fee_bps = config.protocol_fee_bps;
+        self.early_withdrawal_penalty_bps = config.early_withdrawal_penalty_bps;
+        self.insurance_fee_share_bps = config.insurance_fee_share_bps;
+        self.max_coverage_bps = config.max_coverage_bps;
+        Ok(())
+    }
+    
+    /// Chia fee thu được thành (phần treasury, phần insurance fund)
+    pub fn split_fee(&self, amount: u64) -> (u64, u64) {
+        let insurance = mul_div(
+            amount,
+            self.insurance_fee_share_bps as u128,
+            fees::BPS_DIVISOR as u128,
+        );
+        (amount - insurance, insurance)
+    }
+    
+    /// Số lamports tối đa insurance fund bù cho một battle có `principal`
+    pub fn coverage_limit(&self, principal: u64) -> u64 {
+        mul_div(principal, self.max_coverage_bps as u128, fees::BPS_DIVISOR as u128)
+    }
+    
+    /// Số lamports insurance fund bù cho thiếu hụt `shortfall`,
+    /// giới hạn bởi coverage của battle và số dư quỹ
+    pub fn insurance_payout(&self, principal: u64, shortfall: u64) -> u64 {
+        shortfall
+            .min(self.coverage_limit(principal))
+            .min(self.insurance_balance)
+    }
+    
+    /// Tỷ lệ principal (bps) insurance fund hiện có thể bù cho battle
+    pub fn coverage_bps(&self, principal: u64) -> u64 {
+        recovery_bps(
+            self.coverage_limit(principal).min(self.insurance_balance),
+            principal,
+        )
+    }
+    
+    /// Mức bảo hiểm của battle cho client
+    pub fn coverage(&self, battle: &BattleState) -> CoverageSummary {
+        let principal = battle.total_staked();
+        CoverageSummary {
+            battle_id: battle.battle_id,
+            principal,
+            coverage_limit: self.coverage_limit(principal),
+            insurance_balance: self.insurance_balance,
+            coverage_bps: self.coverage_bps(principal),
+        }
+    }
+}
+
+/// ProtocolTreasury: PDA giữ protocol fees (lamports) chuyển từ các vault khi settle
+#[account]
+pub struct ProtocolTreasury {
+    /// Tổng fees đã nhận
+    pub total_collected: u64,
+    
+    /// Tổng đã rút bởi protocol authority
+    pub total_withdrawn: u64,
+    
+    /// Bump seed
+    pub bump: u8,
+}
+
+impl ProtocolTreasury {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // total_collected
+        8 + // total_withdrawn
         1; // bump
 }
+
+/// InsuranceFund: PDA giữ lamports bù thiếu hụt principal khi settle
+/// Nhận một phần protocol fee (`ProtocolState::insurance_fee_share_bps`)
+#[account]
+pub struct InsuranceFund {
+    /// Tổng lamports đã nhận từ protocol fee và `fund_insurance`
+    pub total_deposited: u64,
+    
+    /// Tổng lamports đã bù cho các vault thiếu hụt
+    pub total_paid_out: u64,
+    
+    /// Bump seed
+    pub bump: u8,
+}
+
+impl InsuranceFund {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // total_deposited
+        8 + // total_paid_out
+        1; // bump
+}
+
+/// KeeperSet: nhóm keeper được đăng ký để attest kết quả battle (M-of-N)
+#[account]
+#[derive(Default)]
+pub struct KeeperSet {
+    /// Authority quản lý danh sách keeper
+    pub authority: Pubkey,
+    
+    /// Danh sách keeper (chỉ `keeper_count` phần tử đầu có hiệu lực)
+    pub keepers: [Pubkey; MAX_KEEPERS],
+    
+    /// Số keeper đã đăng ký (N)
+    pub keeper_count: u8,
+    
+    /// Số attestation trùng khớp cần thiết để settle (M)
+    pub threshold: u8,
+    
+    /// Bump seed
+    pub bump: u8,
+}
+
+impl KeeperSet {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // authority
+        32 * MAX_KEEPERS + // keepers
+        1 + // keeper_count
+        1 + // threshold
+        1; // bump
+    
+    /// Kiểm tra danh sách keeper và threshold: 1 <= M <= N <= MAX_KEEPERS, không trùng
+    pub fn validate(keepers: &[Pubkey], threshold: u8) -> Result<()> {
+        require!(
+            !keepers.is_empty() && keepers.len() <= MAX_KEEPERS,
+            MemeWarsError::InvalidKeeperSet
+        );
+        require!(
+            threshold >= 1 && threshold as usize <= keepers.len(),
+            MemeWarsError::InvalidKeeperSet
+        );
+        for (i, keeper) in keepers.iter().enumerate() {
+            require!(
+                *keeper != Pubkey::default() && !keepers[..i].contains(keeper),
+                MemeWarsError::InvalidKeeperSet
+            );
+        }
+        Ok(())
+    }
+    
+    /// Thay danh sách keeper và threshold (đã validate)
+    pub fn set_keepers(&mut self, keepers: &[Pubkey], threshold: u8) -> Result<()> {
+        Self::validate(keepers, threshold)?;
+        self.keepers = [Pubkey::default(); MAX_KEEPERS];
+        self.keepers[..keepers.len()].copy_from_slice(keepers);
+        self.keeper_count = keepers.len() as u8;
+        self.threshold = threshold;
+        Ok(())
+    }
+    
+    /// Các keeper đang có hiệu lực
+    pub fn active_keepers(&self) -> &[Pubkey] {
+        &self.keepers[..self.keeper_count as usize]
+    }
+    
+    pub fn is_keeper(&self, key: &Pubkey) -> bool {
+        self.active_keepers().contains(key)
+    }
+}
+
+/// Kết quả một keeper đã attest
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeeperAttestation {
+    pub keeper: Pubkey,
+    
+    /// Giá trị cuối của token A / metric A
+    pub final_value_a: i64,
+    
+    /// Giá trị cuối của token B / metric B (bỏ qua với bracket battle)
+    pub final_value_b: i64,
+}
+
+/// SettlementAttestations: các attestation đã nộp cho một battle
+#[account]
+#[derive(Default)]
+pub struct SettlementAttestations {
+    /// ID của cuộc chiến
+    pub battle_id: u64,
+    
+    /// Attestation đã nộp (chỉ `count` phần tử đầu có hiệu lực)
+    pub attestations: [KeeperAttestation; MAX_KEEPERS],
+    
+    /// Số attestation đã nộp
+    pub count: u8,
+    
+    /// Bump seed
+    pub bump: u8,
+}
+
+impl SettlementAttestations {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // battle_id
+        (32 + 8 + 8) * MAX_KEEPERS + // attestations
+        1 + // count
+        1; // bump
+    
+    /// Ghi nhận attestation, mỗi keeper chỉ attest một lần
+    /// 
+    /// Attestation của keeper đã bị gỡ khỏi KeeperSet bị bỏ trước khi ghi nhận,
+    /// để không chiếm chỗ trong giới hạn MAX_KEEPERS.
+    pub fn record(&mut self, keeper_set: &KeeperSet, attestation: KeeperAttestation) -> Result<()> {
+        self.prune_inactive(keeper_set);
+        let submitted = &self.attestations[..self.count as usize];
+        require!(
+            !submitted.iter().any(|a| a.keeper == attestation.keeper),
+            MemeWarsError::AlreadyAttested
+        );
+        require!(
+            (self.count as usize) < MAX_KEEPERS,
+            MemeWarsError::InvalidKeeperSet
+        );
+        
+        self.attestations[self.count as usize] = attestation;
+        self.count += 1;
+        Ok(())
+    }
+    
+    /// Bỏ attestation của các keeper không còn trong KeeperSet
+    fn prune_inactive(&mut self, keeper_set: &KeeperSet) {
+        let mut kept = 0;
+        for i in 0..self.count as usize {
+            let attestation = self.attestations[i];
+            if keeper_set.is_keeper(&attestation.keeper) {
+                self.attestations[kept] = attestation;
+                kept += 1;
+            }
+        }
+        self.attestations[kept..].fill(KeeperAttestation::default());
+        self.count = kept as u8;
+    }
+    
+    /// Số keeper (còn trong KeeperSet) đã attest đúng các giá trị này
+    pub fn matching_count(&self, keeper_set: &KeeperSet, final_value_a: i64, final_value_b: i64) -> u8 {
+        self.attestations[..self.count as usize]
+            .iter()
+            .filter(|a| {
+                keeper_set.is_keeper(&a.keeper)
+                    && a.final_value_a == final_value_a
+                    && a.final_value_b == final_value_b
+            })
+            .count() as u8
+    }
+}
+
+/// Tỷ lệ (bps) principal thu hồi được, tối đa `BPS_DIVISOR`
+pub fn recovery_bps(available: u64, principal: u64) -> u64 {
+    if available >= principal {
+        return fees::BPS_DIVISOR;
+    }
+    mul_div(available, fees::BPS_DIVISOR as u128, principal as u128)
+}
+
+/// Áp dụng haircut lên một khoản (làm tròn xuống, có lợi cho vault)
+pub fn apply_haircut(amount: u64, recovery_bps: u64) -> u64 {
+    mul_div(amount, recovery_bps as u128, fees::BPS_DIVISOR as u128)
+}
+
+/// Các lệnh chuyển (from, to, amount) giữa các vault để mỗi vault có ít nhất `targets`
+/// 
+/// Yêu cầu tổng `available` >= tổng `targets` (đúng khi targets đã áp haircut và
+/// yield chỉ chia từ phần vượt principal), phần dư nằm lại ở vault nguồn
+pub fn rebalance_transfers(available: &[u64], targets: &[u64]) -> Vec<(usize, usize, u64)> {
+    let mut balances = available.to_vec();
+    let mut transfers = Vec::new();
+    
+    for to in 0..targets.len() {
+        let mut need = targets[to].saturating_sub(balances[to]);
+        for from in 0..targets.len() {
+            if need == 0 {
+                break;
+            }
+            let amount = balances[from].saturating_sub(targets[from]).min(need);
+            if amount > 0 {
+                balances[from] -= amount;
+                balances[to] += amount;
+                need -= amount;
+                transfers.push((from, to, amount));
+            }
+        }
+    }
+    
+    transfers
+}
+
+/// amount * numerator / denominator, không overflow với lamport-seconds lớn
+/// Khi tích vượt u128 thì giảm độ chính xác của tỷ lệ thay vì thất bại
+fn mul_div(amount: u64, numerator: u128, denominator: u128) -> u64 {
+    let (mut numerator, mut denominator) = (numerator, denominator);
+    loop {
+        match (amount as u128).checked_mul(numerator) {
+            Some(product) => {
+                return product
+                    .checked_div(denominator)
+                    .unwrap_or(0)
+                    .min(u64::MAX as u128) as u64;
+            }
+            None => {
+                numerator >>= 1;
+                denominator >>= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BattlePhase::*;
+
+    const ALL_PHASES: [BattlePhase; 8] = [
+        Scheduled,
+        Open,
+        Locked,
+        AwaitingSettlement,
+        Proposed,
+        NeedsReview,
+        Settled,
+        Cancelled,
+    ];
+
+    const LEGAL_TRANSITIONS: [(BattlePhase, BattlePhase); 16] = [
+        (Scheduled, Open),
+        (Scheduled, Cancelled),
+        (Open, Locked),
+        (Open, AwaitingSettlement),
+        (Open, Cancelled),
+        (Locked, AwaitingSettlement),
+        (Locked, Cancelled),
+        (AwaitingSettlement, Proposed),
+        (AwaitingSettlement, NeedsReview),
+        (AwaitingSettlement, Settled),
+        (AwaitingSettlement, Cancelled),
+        (Proposed, NeedsReview),
+        (Proposed, Settled),
+        (Proposed, Cancelled),
+        (NeedsReview, Proposed),
+        (NeedsReview, Cancelled),
+    ];
+
+    fn battle_in(phase: BattlePhase) -> BattleState {
+        BattleState {
+            phase,
+            start_time: 1_000,
+            end_time: 2_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn legal_transitions_are_accepted() {
+        for (from, to) in LEGAL_TRANSITIONS {
+            let mut battle = battle_in(from);
+            assert!(battle.transition(to).is_ok(), "{from:?} -> {to:?} should be legal");
+            assert_eq!(battle.phase, to);
+        }
+    }
+
+    #[test]
+    fn every_other_transition_is_rejected() {
+        for from in ALL_PHASES {
+            for to in ALL_PHASES {
+                if LEGAL_TRANSITIONS.contains(&(from, to)) {
+                    continue;
+                }
+                let mut battle = battle_in(from);
+                assert!(battle.transition(to).is_err(), "{from:?} -> {to:?} should be illegal");
+                assert_eq!(battle.phase, from);
+            }
+        }
+    }
+
+    #[test]
+    fn terminal_phases_cannot_reopen() {
+        for from in [Settled, Cancelled] {
+            assert!(ALL_PHASES.iter().all(|to| !from.can_transition_to(*to)));
+        }
+    }
+
+    #[test]
+    fn open_battle_awaits_settlement_after_end_time() {
+        let mut battle = battle_in(Open);
+        assert_eq!(battle.effective_phase(1_999), Open);
+        assert_eq!(battle.effective_phase(2_000), AwaitingSettlement);
+
+        battle.sync_phase(2_000).unwrap();
+        assert_eq!(battle.phase, AwaitingSettlement);
+    }
+
+    #[test]
+    fn open_battle_locks_before_end_time() {
+        let mut battle = BattleState {
+            lock_period_seconds: 300,
+            ..battle_in(Open)
+        };
+        assert_eq!(battle.effective_phase(1_699), Open);
+        assert_eq!(battle.effective_phase(1_700), Locked);
+        assert_eq!(battle.effective_phase(2_000), AwaitingSettlement);
+
+        battle.sync_phase(1_800).unwrap();
+        assert_eq!(battle.phase, Locked);
+        battle.sync_phase(2_000).unwrap();
+        assert_eq!(battle.phase, AwaitingSettlement);
+    }
+
+    #[test]
+    fn time_does_not_move_terminal_phases() {
+        for phase in [Settled, Cancelled] {
+            let mut battle = battle_in(phase);
+            battle.sync_phase(i64::MAX).unwrap();
+            assert_eq!(battle.phase, phase);
+        }
+    }
+
+    #[test]
+    fn require_phase_uses_effective_phase() {
+        let battle = battle_in(Open);
+        assert!(battle.require_phase(&[Open], 1_500).is_ok());
+        assert!(battle.require_phase(&[Open], 2_500).is_err());
+        assert!(battle.require_phase(&[AwaitingSettlement], 2_500).is_ok());
+    }
+
+    #[test]
+    fn late_deposit_earns_less_yield() {
+        let mut battle = BattleState {
+            winner: team::TEAM_A,
+            winner_yield: 1_000,
+            ..battle_in(Settled)
+        };
+        let vault = Vault {
+            total_amount: 200,
+            total_shares: 200,
+            ..Default::default()
+        };
+        let early = UserState {
+            team: team::TEAM_A,
+            amount_staked: 100,
+            shares: 100,
+            stake_seconds: battle.stake_weight(100, 1_000).unwrap(),
+            ..Default::default()
+        };
+        // Deposit cùng số tiền ở 90% thời gian battle
+        let late = UserState {
+            team: team::TEAM_A,
+            amount_staked: 100,
+            shares: 100,
+            stake_seconds: battle.stake_weight(100, 1_900).unwrap(),
+            ..Default::default()
+        };
+        battle.add_team_stake_seconds(team::TEAM_A, early.stake_seconds).unwrap();
+        battle.add_team_stake_seconds(team::TEAM_A, late.stake_seconds).unwrap();
+
+        assert_eq!(early.calculate_claim_amount(&battle, &vault), 100 + 909);
+        assert_eq!(late.calculate_claim_amount(&battle, &vault), 100 + 90);
+    }
+
+    #[test]
+    fn pre_season_stake_counts_from_start_time() {
+        let battle = battle_in(Scheduled);
+        assert_eq!(battle.stake_weight(10, 500).unwrap(), 10_000);
+        assert_eq!(battle.stake_weight(10, 1_000).unwrap(), 10_000);
+        assert_eq!(battle.stake_weight(10, 2_500).unwrap(), 0);
+    }
+
+    #[test]
+    fn mul_div_handles_huge_stake_seconds() {
+        let huge = u64::MAX as u128 * 2_592_000;
+        assert_eq!(mul_div(u64::MAX, huge, huge), u64::MAX);
+        assert_eq!(mul_div(1_000, huge / 4, huge), 250);
+        assert_eq!(mul_div(1_000, 1, 0), 0);
+    }
+
+    #[test]
+    fn late_joiner_does_not_capture_earlier_vault_yield() {
+        let mut vault = Vault::default();
+        let early_shares = vault.amount_to_shares(1_000).unwrap();
+        vault.total_amount += 1_000;
+        vault.total_shares += early_shares;
+
+        // Vault lãi 10% trước khi người thứ hai deposit
+        vault.total_amount += 100;
+        let late_shares = vault.amount_to_shares(1_100).unwrap();
+        vault.total_amount += 1_100;
+        vault.total_shares += late_shares;
+
+        assert_eq!(early_shares, 1_000);
+        assert_eq!(late_shares, 1_000);
+        assert_eq!(vault.shares_to_amount(early_shares), 1_100);
+        assert_eq!(vault.shares_to_amount(late_shares), 1_100);
+
+        // Lỗ được chia đều theo shares
+        vault.total_amount -= 220;
+        assert_eq!(vault.shares_to_amount(early_shares), 990);
+    }
+
+    #[test]
+    fn share_rounding_favours_the_vault() {
+        let vault = Vault {
+            total_amount: 1_000,
+            total_shares: 3,
+            ..Default::default()
+        };
+        assert_eq!(vault.amount_to_shares(333).unwrap(), 0);
+        assert_eq!(vault.shares_to_amount(1), 333);
+        assert_eq!(Vault::default().shares_to_amount(10), 0);
+    }
+
+    #[test]
+    fn last_withdrawal_leaves_no_value_for_the_next_depositor() {
+        let mut vault = Vault {
+            total_amount: 1_000,
+            total_shares: 1_000,
+            ..Default::default()
+        };
+        assert_eq!(vault.take_orphaned_amount(), 0);
+
+        // Người cuối rút với penalty 1%, penalty ở lại vault
+        let withdrawn = vault.shares_to_amount(1_000) - 10;
+        vault.total_amount -= withdrawn;
+        vault.total_shares = 0;
+        assert_eq!(vault.take_orphaned_amount(), 10);
+        assert_eq!(vault.total_amount, 0);
+
+        // Depositor tiếp theo chỉ nhận đúng số đã gửi
+        let shares = vault.amount_to_shares(500).unwrap();
+        vault.total_amount += 500;
+        vault.total_shares += shares;
+        assert_eq!(vault.shares_to_amount(shares), 500);
+    }
+
+    #[test]
+    fn emergency_write_down_makes_withdrawals_pro_rata() {
+        let mut vault = Vault {
+            total_amount: 1_000,
+            total_shares: 1_000,
+            lent_amount: 800,
+            msol_balance: 750,
+            lending_position: Some(Pubkey::new_unique()),
+            ..Default::default()
+        };
+
+        // mSOL chưa unstake thì giữ nguyên lending position, không ghi giảm
+        assert_eq!(vault.write_down_to(200), 0);
+        assert_eq!(vault.total_amount, 1_000);
+        assert!(vault.lending_position.is_some());
+
+        // Sau khi liquid unstake: chỉ phần không thu hồi được bị ghi giảm
+        vault.msol_balance = 0;
+        assert_eq!(vault.write_down_to(600), 400);
+        assert!(vault.lending_position.is_none());
+        assert_eq!(vault.total_amount, 600);
+        assert_eq!(vault.lent_amount, 0);
+        assert_eq!(vault.shares_to_amount(250), 150);
+
+        // Thu hồi đủ thì không ghi giảm
+        assert_eq!(vault.write_down_to(5_000), 0);
+        assert_eq!(vault.total_amount, 600);
+    }
+
+    #[test]
+    fn shortfall_haircut_is_equal_across_vaults() {
+        assert_eq!(recovery_bps(1_000, 1_000), fees::BPS_DIVISOR);
+        assert_eq!(recovery_bps(2_000, 1_000), fees::BPS_DIVISOR);
+        assert_eq!(recovery_bps(0, 0), fees::BPS_DIVISOR);
+
+        // Vault A mất 40%, vault B đủ: tổng thu hồi 80% -> cả hai về 80%
+        let available = [600, 1_000];
+        let principal = [1_000, 1_000];
+        let ratio = recovery_bps(available.iter().sum(), principal.iter().sum());
+        assert_eq!(ratio, 8_000);
+
+        let targets: Vec<u64> = principal.iter().map(|p| apply_haircut(*p, ratio)).collect();
+        assert_eq!(targets, vec![800, 800]);
+
+        let transfers = rebalance_transfers(&available, &targets);
+        assert_eq!(transfers, vec![(1, 0, 200)]);
+
+        // Mỗi vault đã đủ thì không chuyển gì
+        assert!(rebalance_transfers(&[500, 700], &[500, 700]).is_empty());
+    }
+
+    #[test]
+    fn rebalance_covers_uneven_vaults_without_a_shortfall() {
+        // Lending của A lỗ 100, của B lãi 300: tổng vẫn dư nên không haircut,
+        // nhưng A phải được bù từ B; phần dư 200 nằm lại ở B
+        let transfers = rebalance_transfers(&[900, 1_300], &[1_000, 1_000]);
+        assert_eq!(transfers, vec![(1, 0, 100)]);
+
+        // Yield của winner (A) được chuyển từ vault của loser
+        let transfers = rebalance_transfers(&[1_000, 1_200], &[1_150, 1_000]);
+        assert_eq!(transfers, vec![(1, 0, 150)]);
+    }
+
+    #[test]
+    fn protocol_fee_from_realised_yield_is_left_as_excess() {
+        const RENT: u64 = 100;
+        let mut battle = BattleState {
+            winner: team::TEAM_A,
+            protocol_fee_bps: 500,
+            ..battle_in(Settled)
+        };
+        let (winner_yield, protocol_fee) = battle.split_yield(100);
+        assert_eq!((winner_yield, protocol_fee), (95, 5));
+        battle.winner_yield = winner_yield;
+
+        let vault = |team: u8| Vault {
+            team,
+            total_amount: 1_000,
+            yield_collected: battle.team_yield(team),
+            ..Default::default()
+        };
+        let (vault_a, vault_b) = (vault(team::TEAM_A), vault(team::TEAM_B));
+        let targets = [vault_a.claimable_amount(), vault_b.claimable_amount()];
+        assert_eq!(targets, [1_095, 1_000]);
+
+        // Yield về vault của winner: fee nằm lại làm excess của vault A
+        assert!(rebalance_transfers(&[1_100, 1_000], &targets).is_empty());
+        assert_eq!(vault_a.excess_lamports(1_100 + RENT, RENT), protocol_fee);
+
+        // Yield về vault của loser: winner yield được chuyển sang, fee ở lại vault B
+        assert_eq!(rebalance_transfers(&[1_000, 1_100], &targets), vec![(1, 0, 95)]);
+        assert_eq!(vault_a.excess_lamports(1_095 + RENT, RENT), 0);
+        assert_eq!(vault_b.excess_lamports(1_005 + RENT, RENT), protocol_fee);
+    }
+
+    #[test]
+    fn fee_excess_excludes_everything_owed_to_players() {
+        let vault = Vault {
+            total_amount: 10_000,
+            yield_collected: 500,
+            claimed_amount: 2_000,
+            ..Default::default()
+        };
+        // Rent 100 + còn nợ 8_500 (principal + yield chưa claim)
+        assert_eq!(vault.excess_lamports(8_600, 100), 0);
+        assert_eq!(vault.excess_lamports(8_900, 100), 300);
+    }
+
+    #[test]
+    fn never_started_battles_cancel_permissionlessly() {
+        assert_eq!(battle_in(Scheduled).permissionless_cancel_reason(1_500), None);
+        assert_eq!(
+            battle_in(Scheduled).permissionless_cancel_reason(2_000),
+            Some(cancel_reason::NEVER_STARTED)
+        );
+        assert_eq!(battle_in(Open).permissionless_cancel_reason(1_500), None);
+        assert_eq!(battle_in(Settled).permissionless_cancel_reason(i64::MAX), None);
+    }
+
+    #[test]
+    fn unsettled_battles_cancel_permissionlessly_after_force_resolve_window() {
+        let deadline = 2_000 + time::SETTLEMENT_GRACE_PERIOD + time::FORCE_RESOLVE_WINDOW;
+        assert_eq!(battle_in(Open).force_resolve_deadline(), deadline);
+        assert_eq!(battle_in(Open).permissionless_cancel_reason(2_500), None);
+        assert_eq!(
+            battle_in(Open).permissionless_cancel_reason(deadline),
+            Some(cancel_reason::SETTLEMENT_TIMEOUT)
+        );
+        assert_eq!(battle_in(Proposed).permissionless_cancel_reason(i64::MAX), None);
+    }
+
+    #[test]
+    fn admin_can_force_resolve_before_anyone_can_cancel() {
+        let battle = battle_in(AwaitingSettlement);
+        // Cả khoảng force_resolve thuộc về admin, cancel chỉ mở khi khoảng này đóng
+        for now in [battle.settlement_deadline(), battle.force_resolve_deadline() - 1] {
+            assert!(battle.can_force_resolve(now));
+            assert_eq!(battle.permissionless_cancel_reason(now), None);
+        }
+        let deadline = battle.force_resolve_deadline();
+        assert!(!battle.can_force_resolve(battle.settlement_deadline() - 1));
+        assert!(!battle.can_force_resolve(deadline));
+        assert_eq!(
+            battle.permissionless_cancel_reason(deadline),
+            Some(cancel_reason::SETTLEMENT_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn cancel_records_reason_once() {
+        let mut battle = battle_in(Open);
+        battle.cancel(cancel_reason::AUTHORITY).unwrap();
+        assert_eq!(battle.phase, Cancelled);
+        assert_eq!(battle.cancel_reason, cancel_reason::AUTHORITY);
+        assert!(battle.cancel(cancel_reason::AUTHORITY).is_err());
+    }
+
+    fn settled_prices(final_a: i64, final_b: i64) -> BattleState {
+        BattleState {
+            initial_price_a: 100,
+            initial_price_b: 100,
+            final_price_a: Some(final_a),
+            final_price_b: Some(final_b),
+            ..battle_in(AwaitingSettlement)
+        }
+    }
+
+    #[test]
+    fn handicap_shifts_the_result_towards_either_side() {
+        // A +10%, B +5%
+        let mut battle = settled_prices(110, 105);
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+
+        // Handicap dương cộng cho B: B thành +11%
+        battle.handicap_bps = 600;
+        assert_eq!(battle.determine_winner(), team::TEAM_B);
+
+        // Handicap âm trừ của B, giúp A thắng dù B tăng nhiều hơn
+        let mut battle = settled_prices(105, 110);
+        assert_eq!(battle.determine_winner(), team::TEAM_B);
+        battle.handicap_bps = -600;
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+    }
+
+    #[test]
+    fn maximum_handicap_is_applied_in_full() {
+        // A +150%, B +50% cộng handicap 100% -> bằng điểm
+        let mut battle = settled_prices(250, 150);
+        battle.handicap_bps = scoring::MAX_HANDICAP_BPS;
+        assert_eq!(battle.determine_winner(), team::NONE);
+        battle.final_price_a = Some(251);
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+
+        // B +50% trừ handicap 100% vẫn thua A đứng giá
+        let mut battle = settled_prices(100, 150);
+        battle.handicap_bps = -scoring::MAX_HANDICAP_BPS;
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+        assert_eq!(
+            battle.handicapped_score_b(0),
+            -math::bps_to_scaled(scoring::MAX_HANDICAP_BPS)
+        );
+    }
+
+    #[test]
+    fn growth_difference_inside_draw_band_is_a_tie() {
+        // A +10%, B +9%: chênh lệch đúng bằng draw band 1%
+        let mut battle = BattleState {
+            initial_price_a: 10_000,
+            initial_price_b: 10_000,
+            tie_tolerance_bps: 100,
+            ..settled_prices(11_000, 10_900)
+        };
+        assert_eq!(battle.determine_winner(), team::NONE);
+
+        battle.final_price_b = Some(10_899);
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+
+        battle.final_price_a = Some(10_798);
+        assert_eq!(battle.determine_winner(), team::TEAM_B);
+
+        battle.tie_tolerance_bps = 0;
+        battle.final_price_a = Some(10_899);
+        assert_eq!(battle.determine_winner(), team::NONE);
+    }
+
+    #[test]
+    fn overlapping_confidence_intervals_are_a_tie() {
+        // A: [9.5%, 10.5%], B: [7%, 9%]
+        let mut battle = BattleState {
+            initial_price_a: 10_000,
+            initial_price_b: 10_000,
+            final_conf_a: 50,
+            final_conf_b: 100,
+            confidence_ties: true,
+            ..settled_prices(11_000, 10_800)
+        };
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+
+        // B: [6.5%, 9.5%] chạm cận dưới của A
+        battle.final_conf_b = 150;
+        assert_eq!(battle.determine_winner(), team::NONE);
+
+        // Handicap cũng dịch khoảng confidence của B
+        battle.handicap_bps = -300;
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+
+        // Tắt confidence_ties thì chỉ so giá cuối
+        battle.handicap_bps = 0;
+        battle.confidence_ties = false;
+        assert_eq!(battle.determine_winner(), team::TEAM_A);
+    }
+
+    fn bracket_battle() -> BattleState {
+        let mut battle = BattleState {
+            kind: battle_kind::BRACKET,
+            bracket_count: 4,
+            initial_price_a: 1_000,
+            ..battle_in(AwaitingSettlement)
+        };
+        battle.bracket_bounds[..3].copy_from_slice(&[-1_000, 0, 1_000]);
+        battle
+    }
+
+    #[test]
+    fn bracket_bounds_are_inclusive_lower_edges() {
+        let mut battle = bracket_battle();
+        let bps = math::bps_to_scaled;
+        assert_eq!(battle.bracket_for_score(bps(-5_000)), 1);
+        assert_eq!(battle.bracket_for_score(bps(-1_000) - 1), 1);
+        assert_eq!(battle.bracket_for_score(bps(-1_000)), 2);
+        assert_eq!(battle.bracket_for_score(0), 3);
+        assert_eq!(battle.bracket_for_score(bps(999)), 3);
+        assert_eq!(battle.bracket_for_score(bps(1_000)), 4);
+        assert_eq!(battle.bracket_for_score(i128::MAX), 4);
+
+        // Bound ngoài bracket_count bị bỏ qua
+        battle.bracket_bounds[3] = -10_000;
+        assert_eq!(battle.bracket_for_score(i128::MAX), 4);
+    }
+
+    #[test]
+    fn empty_winning_bracket_resolves_to_a_draw() {
+        let mut battle = bracket_battle();
+        battle.bracket_staked = [100, 0, 50, 0, 0, 0, 0, 0];
+        assert_eq!(battle.resolve_bracket_winner(math::bps_to_scaled(-2_000)), 1);
+        assert_eq!(battle.resolve_bracket_winner(math::bps_to_scaled(-500)), team::NONE);
+        assert_eq!(battle.resolve_bracket_winner(math::bps_to_scaled(500)), 3);
+        assert_eq!(battle.resolve_bracket_winner(math::bps_to_scaled(5_000)), team::NONE);
+
+        // +5% -> bracket 3
+        battle.final_price_a = Some(1_050);
+        assert_eq!(battle.determine_winner(), 3);
+        battle.final_price_a = None;
+        assert_eq!(battle.determine_winner(), team::NONE);
+    }
+
+    #[test]
+    fn start_crank_must_run_within_start_window() {
+        let battle = battle_in(Scheduled);
+        assert_eq!(battle.start_deadline(), 1_000 + time::START_WINDOW);
+        assert_eq!(battle.permissionless_cancel_reason(1_000), None);
+        assert_eq!(battle.permissionless_cancel_reason(battle.start_deadline() - 1), None);
+        assert_eq!(
+            battle.permissionless_cancel_reason(battle.start_deadline()),
+            Some(cancel_reason::NEVER_STARTED)
+        );
+
+        // Battle đã start thì không còn bị hủy vì quá START_WINDOW
+        assert_eq!(battle_in(Open).permissionless_cancel_reason(battle.start_deadline()), None);
+
+        // Window không vượt quá end_time
+        let short = BattleState {
+            end_time: 1_000 + time::START_WINDOW / 2,
+            ..battle
+        };
+        assert_eq!(short.start_deadline(), short.end_time);
+    }
+
+    #[test]
+    fn one_sided_battles_lack_participation() {
+        let mut battle = BattleState {
+            total_staked_a: 500,
+            ..battle_in(AwaitingSettlement)
+        };
+        assert!(!battle.has_min_participation());
+
+        battle.total_staked_b = 100;
+        assert!(battle.has_min_participation());
+
+        battle.min_team_stake = 200;
+        assert!(!battle.has_min_participation());
+
+        let mut bracket = BattleState {
+            kind: battle_kind::BRACKET,
+            bracket_count: 4,
+            ..battle_in(AwaitingSettlement)
+        };
+        bracket.bracket_staked[2] = 1_000;
+        assert!(!bracket.has_min_participation());
+        bracket.bracket_staked[0] = 1;
+        assert!(bracket.has_min_participation());
+    }
+
+    #[test]
+    fn circuit_breaker_trips_on_extreme_growth_and_ema_divergence() {
+        let mut battle = BattleState {
+            initial_price_a: 100,
+            initial_price_b: 100,
+            final_price_a: Some(5_100),
+            final_price_b: Some(110),
+            ..battle_in(AwaitingSettlement)
+        };
+        // Tắt mặc định
+        assert_eq!(battle.circuit_breaker_reason(None, None), review_reason::NONE);
+
+        battle.max_growth_bps = 600_000;
+        assert_eq!(battle.circuit_breaker_reason(None, None), review_reason::NONE);
+        battle.max_growth_bps = 100_000;
+        assert_eq!(battle.circuit_breaker_reason(None, None), review_reason::GROWTH_LIMIT);
+
+        battle.max_growth_bps = 0;
+        battle.max_ema_divergence_bps = 2_000;
+        assert_eq!(battle.circuit_breaker_reason(Some(5_000), Some(105)), review_reason::NONE);
+        assert_eq!(
+            battle.circuit_breaker_reason(Some(5_000), Some(50)),
+            review_reason::EMA_DIVERGENCE
+        );
+        // Feed không có EMA thì bỏ qua
+        assert_eq!(battle.circuit_breaker_reason(None, None), review_reason::NONE);
+    }
+
+    #[test]
+    fn unresolved_review_cancels_after_deadline() {
+        let battle = BattleState {
+            review_deadline: 5_000,
+            ..battle_in(NeedsReview)
+        };
+        assert_eq!(battle.permissionless_cancel_reason(4_999), None);
+        assert_eq!(
+            battle.permissionless_cancel_reason(5_000),
+            Some(cancel_reason::REVIEW_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn roles_separate_settlement_from_treasury_and_creation() {
+        let admin = Pubkey::new_unique();
+        let keeper = Pubkey::new_unique();
+        let treasurer = Pubkey::new_unique();
+        let creator = Pubkey::new_unique();
+        let mut protocol_state = ProtocolState {
+            authority: admin,
+            ..Default::default()
+        };
+
+        assert!(protocol_state
+            .set_roles(&RoleParams {
+                battle_creator: creator,
+                settler: keeper,
+                treasury_manager: Pubkey::default(),
+            })
+            .is_err());
+        protocol_state
+            .set_roles(&RoleParams {
+                battle_creator: creator,
+                settler: keeper,
+                treasury_manager: treasurer,
+            })
+            .unwrap();
+
+        let battle = BattleState {
+            authority: creator,
+            ..battle_in(NeedsReview)
+        };
+        assert!(protocol_state.can_settle(&battle, &keeper));
+        assert!(protocol_state.can_settle(&battle, &creator));
+        assert!(!protocol_state.can_settle(&battle, &treasurer));
+
+        assert!(protocol_state.can_create_battles(&creator));
+        assert!(protocol_state.can_create_battles(&admin));
+        assert!(!protocol_state.can_create_battles(&keeper));
+        protocol_state.battle_creator = Pubkey::default();
+        assert!(protocol_state.can_create_battles(&keeper));
+    }
+
+    #[test]
+    fn protocol_config_is_bounded_by_hard_maximums() {
+        let mut protocol_state = ProtocolState::default();
+        let config = ConfigParams {
+            protocol_fee_bps: fees::MAX_PROTOCOL_FEE_BPS,
+            early_withdrawal_penalty_bps: fees::MAX_EARLY_WITHDRAWAL_PENALTY_BPS,
+            insurance_fee_share_bps: fees::BPS_DIVISOR,
+            max_coverage_bps: fees::BPS_DIVISOR,
+        };
+        assert!(protocol_state.set_config(&config).is_ok());
+        assert_eq!(protocol_state.protocol_fee_bps, fees::MAX_PROTOCOL_FEE_BPS);
+
+        assert!(protocol_state
+            .set_config(&ConfigParams {
+                protocol_fee_bps: fees::MAX_PROTOCOL_FEE_BPS + 1,
+                ..config
+            })
+            .is_err());
+        assert!(protocol_state
+            .set_config(&ConfigParams {
+                early_withdrawal_penalty_bps: fees::MAX_EARLY_WITHDRAWAL_PENALTY_BPS + 1,
+                ..config
+            })
+            .is_err());
+        assert!(protocol_state
+            .set_config(&ConfigParams {
+                insurance_fee_share_bps: fees::BPS_DIVISOR + 1,
+                ..config
+            })
+            .is_err());
+        assert!(protocol_state
+            .set_config(&ConfigParams {
+                max_coverage_bps: fees::BPS_DIVISOR + 1,
+                ..config
+            })
+            .is_err());
+        assert_eq!(
+            protocol_state.early_withdrawal_penalty_bps,
+            fees::MAX_EARLY_WITHDRAWAL_PENALTY_BPS
+        );
+    }
+
+    #[test]
+    fn insurance_covers_shortfall_up_to_limit_and_balance() {
+        let protocol_state = ProtocolState {
+            insurance_fee_share_bps: 2_000,
+            max_coverage_bps: 1_000,
+            insurance_balance: 50,
+            ..Default::default()
+        };
+        assert_eq!(protocol_state.split_fee(1_001), (801, 200));
+
+        // Principal 1_000 -> coverage tối đa 100, quỹ chỉ còn 50
+        assert_eq!(protocol_state.coverage_limit(1_000), 100);
+        assert_eq!(protocol_state.insurance_payout(1_000, 30), 30);
+        assert_eq!(protocol_state.insurance_payout(1_000, 80), 50);
+        assert_eq!(protocol_state.coverage_bps(1_000), 500);
+
+        let funded = ProtocolState {
+            insurance_balance: 1_000,
+            ..protocol_state
+        };
+        assert_eq!(funded.insurance_payout(1_000, 300), 100);
+        assert_eq!(funded.coverage_bps(1_000), 1_000);
+        assert_eq!(funded.coverage_bps(0), fees::BPS_DIVISOR);
+    }
+
+    #[test]
+    fn refund_window_covers_cancelled_and_force_resolved_battles() {
+        assert!(battle_in(Cancelled).is_refund_window());
+        assert!(!battle_in(Open).is_refund_window());
+        assert!(!battle_in(Proposed).is_refund_window());
+
+        let forced = BattleState {
+            force_resolved: true,
+            ..battle_in(Proposed)
+        };
+        assert!(forced.is_refund_window());
+    }
+
+    #[test]
+    fn keeper_set_validates_threshold_and_duplicates() {
+        let keepers: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        assert!(KeeperSet::validate(&keepers, 2).is_ok());
+        assert!(KeeperSet::validate(&keepers, 0).is_err());
+        assert!(KeeperSet::validate(&keepers, 4).is_err());
+        assert!(KeeperSet::validate(&[], 1).is_err());
+        assert!(KeeperSet::validate(&[keepers[0], keepers[0]], 1).is_err());
+        assert!(KeeperSet::validate(&[Pubkey::default()], 1).is_err());
+
+        let too_many: Vec<Pubkey> = (0..MAX_KEEPERS + 1).map(|_| Pubkey::new_unique()).collect();
+        assert!(KeeperSet::validate(&too_many, 1).is_err());
+    }
+
+    #[test]
+    fn attestations_count_only_matching_active_keepers() {
+        let keepers: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let mut keeper_set = KeeperSet::default();
+        keeper_set.set_keepers(&keepers, 2).unwrap();
+
+        let mut attestations = SettlementAttestations::default();
+        let attest = |keeper: Pubkey, a: i64| KeeperAttestation {
+            keeper,
+            final_value_a: a,
+            final_value_b: 100,
+        };
+        attestations.record(&keeper_set, attest(keepers[0], 120)).unwrap();
+        attestations.record(&keeper_set, attest(keepers[1], 130)).unwrap();
+        assert!(attestations.record(&keeper_set, attest(keepers[0], 130)).is_err());
+        assert_eq!(attestations.matching_count(&keeper_set, 130, 100), 1);
+
+        attestations.record(&keeper_set, attest(keepers[2], 130)).unwrap();
+        assert_eq!(attestations.matching_count(&keeper_set, 130, 100), 2);
+
+        // Keeper bị gỡ khỏi set thì attestation không còn được tính
+        keeper_set.set_keepers(&[keepers[0], keepers[2]], 2).unwrap();
+        assert_eq!(attestations.matching_count(&keeper_set, 130, 100), 1);
+    }
+
+    #[test]
+    fn removed_keepers_free_their_attestation_slots() {
+        let keepers: Vec<Pubkey> = (0..MAX_KEEPERS).map(|_| Pubkey::new_unique()).collect();
+        let mut keeper_set = KeeperSet::default();
+        keeper_set.set_keepers(&keepers, 1).unwrap();
+
+        let mut attestations = SettlementAttestations::default();
+        let attest = |keeper: Pubkey| KeeperAttestation {
+            keeper,
+            final_value_a: 120,
+            final_value_b: 100,
+        };
+        for keeper in &keepers {
+            attestations.record(&keeper_set, attest(*keeper)).unwrap();
+        }
+        assert_eq!(attestations.count as usize, MAX_KEEPERS);
+
+        // Thay toàn bộ keeper: attestation cũ bị bỏ, keeper mới vẫn attest được
+        let replacement = Pubkey::new_unique();
+        keeper_set.set_keepers(&[replacement], 1).unwrap();
+        attestations.record(&keeper_set, attest(replacement)).unwrap();
+        assert_eq!(attestations.count, 1);
+        assert_eq!(attestations.attestations[0].keeper, replacement);
+        assert_eq!(attestations.matching_count(&keeper_set, 120, 100), 1);
+    }
+}
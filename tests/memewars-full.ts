@@ -5,6 +5,20 @@ import { PublicKey, Keypair, LAMPORTS_PER_SOL, SystemProgram } from "@solana/web
 import { TOKEN_PROGRAM_ID, getAssociatedTokenAddress, createAssociatedTokenAccountInstruction } from "@solana/spl-token";
 import { assert } from "chai";
 
+// Mock Pyth feeds nạp từ tests/fixtures qua Anchor.toml ([[test.validator.account]])
+const MOCK_PRICE_FEED_A = new PublicKey("5CQPJGdXdHkj5G59fcA3hEDFUcL7mBGgZuzGc1Ge1Fv1");
+const MOCK_PRICE_FEED_B = new PublicKey("CzF2XKUZYtVQ5PJ6bZmc38tdPvV6MtQMLsRE1zK8frB7");
+
+async function expectError(tx: Promise<unknown>, code: string) {
+  try {
+    await tx;
+  } catch (err) {
+    assert.include(String(err), code);
+    return;
+  }
+  assert.fail(`expected ${code}`);
+}
+
 describe("MemeWars Full Test Suite", () => {
   // Configure the client
   const provider = anchor.AnchorProvider.env();
@@ -12,6 +26,12 @@ describe("MemeWars Full Test Suite", () => {
 
   const program = anchor.workspace.Memewars as Program<Memewars>;
   const user = provider.wallet;
+
+  // ProgramData của program: chỉ upgrade authority (wallet deploy) được initialize
+  const [programDataPDA] = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+  );
   
   // Test data
   const battleId = new anchor.BN(Date.now()); // Unique battle ID
@@ -22,22 +42,36 @@ describe("MemeWars Full Test Suite", () => {
 
   // PDAs
   let battlePDA: PublicKey;
+  let protocolStatePDA: PublicKey;
+  let treasuryPDA: PublicKey;
+  let insuranceFundPDA: PublicKey;
   let vaultAPDA: PublicKey;
   let vaultBPDA: PublicKey;
   let userStatePDA: PublicKey;
   let ticketMintPDA: PublicKey;
   let ticketMintAuthorityPDA: PublicKey;
 
-  // Mock price feeds (in production, use real Pyth accounts)
-  const mockPriceFeedA = Keypair.generate();
-  const mockPriceFeedB = Keypair.generate();
-
   // Token mints (mock)
   const tokenAMint = Keypair.generate();
   const tokenBMint = Keypair.generate();
 
   before(async () => {
     // Derive PDAs
+    [protocolStatePDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_state")],
+      program.programId
+    );
+
+    [treasuryPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_treasury")],
+      program.programId
+    );
+
+    [insuranceFundPDA] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_fund")],
+      program.programId
+    );
+
     [battlePDA] = PublicKey.findProgramAddressSync(
       [Buffer.from("battle"), battleId.toArrayLike(Buffer, "le", 8)],
       program.programId
@@ -104,6 +138,22 @@ describe("MemeWars Full Test Suite", () => {
     
     const balance = await provider.connection.getBalance(user.publicKey);
     console.log(`User balance: ${balance / LAMPORTS_PER_SOL} SOL`);
+
+    // Protocol chỉ được khởi tạo một lần cho cả test run
+    if (!(await provider.connection.getAccountInfo(protocolStatePDA))) {
+      await program.methods
+        .initialize()
+        .accountsPartial({
+          authority: user.publicKey,
+          program: program.programId,
+          programData: programDataPDA,
+          protocolState: protocolStatePDA,
+          treasury: treasuryPDA,
+          insuranceFund: insuranceFundPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
   });
 
   describe("create_battle", () => {
@@ -118,14 +168,16 @@ describe("MemeWars Full Test Suite", () => {
       
       // In a real test, you would do:
       // const tx = await program.methods
-      //   .createBattle(battleId, new anchor.BN(BATTLE_DURATION))
+      //   .createBattle(battleId, new anchor.BN(BATTLE_DURATION), { handicapBps: new anchor.BN(0), tieToleranceBps: new anchor.BN(0), confidenceTies: false, scoringMode: 0 }, { startTime: new anchor.BN(0), preSeasonDeposits: false, lockPeriodSeconds: new anchor.BN(0) }, { minTeamStake: new anchor.BN(0), maxGrowthBps: new anchor.BN(0), maxEmaDivergenceBps: new anchor.BN(0) })
       //   .accounts({
       //     authority: user.publicKey,
       //     battle: battlePDA,
+      //     protocolState: protocolStatePDA,
       //     tokenA: tokenAMint.publicKey,
       //     tokenB: tokenBMint.publicKey,
       //     priceFeedA: PYTH_BONK_USD,
       //     priceFeedB: PYTH_WIF_USD,
+      //     keeperSet: null,
       //     systemProgram: SystemProgram.programId,
       //   })
       //   .rpc();
@@ -145,6 +197,7 @@ describe("MemeWars Full Test Suite", () => {
       //   .accounts({
       //     user: user.publicKey,
       //     battle: battlePDA,
+      //     protocolState: protocolStatePDA,
       //     userState: userStatePDA,
       //     vault: vaultAPDA,
       //     ticketMint: ticketMintPDA,
@@ -159,25 +212,190 @@ describe("MemeWars Full Test Suite", () => {
   });
 
   describe("settle", () => {
-    it("Should settle a battle after end time", async () => {
+    it("Should propose and finalize settlement after end time", async () => {
       console.log("\n⚠️  Skipping settle test - requires battle to be ended");
-      console.log("Flow: Wait for end_time -> settle -> claim_reward");
+      console.log("Flow: Wait for end_time -> propose_settlement -> dispute window -> finalize_settlement -> claim_reward");
       
       // Example settle:
-      // const tx = await program.methods
-      //   .settle()
+      // await program.methods
+      //   .proposeSettlement()
       //   .accounts({
-      //     authority: user.publicKey,
+      //     proposer: user.publicKey,
+      //     battle: battlePDA,
+      //     priceFeedA: PYTH_BONK_USD,
+      //     priceFeedB: PYTH_WIF_USD,
+      //   })
+      //   .rpc();
+      //
+      // // claim_reward is rejected with InvalidBattlePhase until the window closes
+      //
+      // // ... wait DISPUTE_WINDOW (1 hour) ...
+      // await program.methods
+      //   .finalizeSettlement()
+      //   .accounts({
+      //     finalizer: user.publicKey,
       //     battle: battlePDA,
+      //     protocolState: protocolStatePDA,
+      //     treasury: treasuryPDA,
+      //     insuranceFund: insuranceFundPDA,
+      //     proposer: user.publicKey,
       //     vaultA: vaultAPDA,
       //     vaultB: vaultBPDA,
+      //   })
+      //   .rpc();
+    });
+
+    describe("by a random signer", () => {
+      const settleBattleId = new anchor.BN(Date.now() + 1);
+      const keeper = Keypair.generate();
+      let settleBattlePDA: PublicKey;
+
+      before(async () => {
+        [settleBattlePDA] = PublicKey.findProgramAddressSync(
+          [Buffer.from("battle"), settleBattleId.toArrayLike(Buffer, "le", 8)],
+          program.programId
+        );
+
+        const sig = await provider.connection.requestAirdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+        await provider.connection.confirmTransaction(sig);
+
+        await program.methods
+          .createBattle(
+            settleBattleId,
+            new anchor.BN(BATTLE_DURATION),
+            { handicapBps: new anchor.BN(0), tieToleranceBps: new anchor.BN(0), confidenceTies: false, scoringMode: 0 },
+            { startTime: new anchor.BN(0), preSeasonDeposits: false, lockPeriodSeconds: new anchor.BN(0) },
+            { minTeamStake: new anchor.BN(0), maxGrowthBps: new anchor.BN(0), maxEmaDivergenceBps: new anchor.BN(0) }
+          )
+          .accountsPartial({
+            authority: user.publicKey,
+            battle: settleBattlePDA,
+            protocolState: protocolStatePDA,
+            tokenA: tokenAMint.publicKey,
+            tokenB: tokenBMint.publicKey,
+            priceFeedA: MOCK_PRICE_FEED_A,
+            priceFeedB: MOCK_PRICE_FEED_B,
+            keeperSet: null,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+      });
+
+      it("Should reject price feeds not bound to the battle", async () => {
+        await expectError(
+          program.methods
+            .proposeSettlement()
+            .accountsPartial({
+              proposer: keeper.publicKey,
+              battle: settleBattlePDA,
+              priceFeedA: MOCK_PRICE_FEED_B,
+              priceFeedB: MOCK_PRICE_FEED_A,
+            })
+            .signers([keeper])
+            .rpc(),
+          "InvalidPriceFeed"
+        );
+      });
+
+      it("Should reject a proposal before end_time", async () => {
+        await expectError(
+          program.methods
+            .proposeSettlement()
+            .accountsPartial({
+              proposer: keeper.publicKey,
+              battle: settleBattlePDA,
+              priceFeedA: MOCK_PRICE_FEED_A,
+              priceFeedB: MOCK_PRICE_FEED_B,
+            })
+            .signers([keeper])
+            .rpc(),
+          "InvalidBattlePhase"
+        );
+      });
+
+      it("Should reject a dispute when nothing is proposed", async () => {
+        await expectError(
+          program.methods
+            .disputeSettlement()
+            .accountsPartial({
+              challenger: keeper.publicKey,
+              battle: settleBattlePDA,
+              priceFeedA: MOCK_PRICE_FEED_A,
+              priceFeedB: MOCK_PRICE_FEED_B,
+              systemProgram: SystemProgram.programId,
+            })
+            .signers([keeper])
+            .rpc(),
+          "InvalidBattlePhase"
+        );
+      });
+
+      it("Should reject a finalize naming a proposer that never proposed", async () => {
+        await expectError(
+          program.methods
+            .finalizeSettlement()
+            .accountsPartial({
+              finalizer: keeper.publicKey,
+              battle: settleBattlePDA,
+              protocolState: protocolStatePDA,
+              treasury: treasuryPDA,
+              insuranceFund: insuranceFundPDA,
+              proposer: keeper.publicKey,
+              vaultA: null,
+              vaultB: null,
+            })
+            .signers([keeper])
+            .rpc(),
+          "Unauthorized"
+        );
+      });
+    });
+
+    it("Should let a bonded challenger dispute a proposed result", async () => {
+      console.log("\n⚠️  Skipping dispute test - requires a proposed settlement");
+      console.log("Challenger posts a 0.1 SOL bond; a different winner replaces the proposal");
+      
+      // Example dispute:
+      // const challenger = Keypair.generate();
+      // await program.methods
+      //   .disputeSettlement()
+      //   .accounts({
+      //     challenger: challenger.publicKey,
+      //     battle: battlePDA,
       //     priceFeedA: PYTH_BONK_USD,
       //     priceFeedB: PYTH_WIF_USD,
+      //     systemProgram: SystemProgram.programId,
       //   })
+      //   .signers([challenger])
       //   .rpc();
     });
   });
 
+  describe("fund_insurance", () => {
+    it("Should add funded lamports to the insurance balance", async () => {
+      const amount = new anchor.BN(LAMPORTS_PER_SOL / 10);
+      const before = await program.account.protocolState.fetch(protocolStatePDA);
+      const lamportsBefore = await provider.connection.getBalance(insuranceFundPDA);
+
+      await program.methods
+        .fundInsurance(amount)
+        .accountsPartial({
+          funder: user.publicKey,
+          protocolState: protocolStatePDA,
+          insuranceFund: insuranceFundPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      const after = await program.account.protocolState.fetch(protocolStatePDA);
+      assert.equal(after.insuranceBalance.sub(before.insuranceBalance).toString(), amount.toString());
+      assert.equal(
+        await provider.connection.getBalance(insuranceFundPDA),
+        lamportsBefore + amount.toNumber()
+      );
+    });
+  });
+
   describe("claim_reward", () => {
     it("Should claim reward after battle is settled", async () => {
       console.log("\n⚠️  Skipping claim_reward test - requires settled battle");
@@ -190,6 +408,7 @@ describe("MemeWars Full Test Suite", () => {
       //   .accounts({
       //     user: user.publicKey,
       //     battle: battlePDA,
+      //     protocolState: protocolStatePDA,
       //     userState: userStatePDA,
       //     vault: vaultAPDA,
       //     ticketMint: ticketMintPDA,
@@ -212,6 +431,9 @@ describe("MemeWars Full Test Suite", () => {
       //   .accounts({
       //     user: user.publicKey,
       //     battle: battlePDA,
+      //     protocolState: protocolStatePDA,
+      //     treasury: treasuryPDA,
+      //     insuranceFund: insuranceFundPDA,
       //     userState: userStatePDA,
       //     vault: vaultAPDA,
       //     ticketMint: ticketMintPDA,
@@ -234,7 +456,7 @@ describe("Battle Lifecycle Integration", () => {
     console.log("1. Admin creates battle with Pyth price feeds");
     console.log("2. Users deposit SOL and choose teams");
     console.log("3. Wait for battle to end (7 days)");
-    console.log("4. Admin settles battle using final Pyth prices");
+    console.log("4. Anyone proposes the result using final Pyth prices, disputes run for 1 hour, then it is finalized (keeper bounty)");
     console.log("5. Winner is determined by price growth %");
     console.log("6. Winners claim principal + yield");
     console.log("7. Losers claim principal (NO LOSS!)");
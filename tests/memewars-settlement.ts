@@ -0,0 +1,364 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { BankrunProvider } from "anchor-bankrun";
+import { AddedAccount, Clock, ProgramTestContext, start } from "solana-bankrun";
+import { Keypair, LAMPORTS_PER_SOL, PublicKey, SystemProgram } from "@solana/web3.js";
+import { TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { assert } from "chai";
+import * as fs from "fs";
+import { Memewars } from "../target/types/memewars";
+import IDL from "../target/idl/memewars.json";
+
+// Settlement chạy trên bankrun để tua clock qua end_time và dispute window,
+// giá Pyth là PriceUpdateV2 giả lập thuộc Pyth receiver
+const BPF_LOADER_UPGRADEABLE = new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
+const PYTH_RECEIVER = new PublicKey("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LSSx");
+const PRICE_UPDATE_V2_DISCRIMINATOR = Buffer.from([34, 241, 35, 99, 157, 126, 244, 205]);
+const POSTED_SLOT_ALWAYS_FRESH = BigInt("18446744073709551615");
+
+const FEED_A = 1;
+const FEED_B = 2;
+const TEAM_A = 1;
+const TEAM_B = 2;
+const BATTLE_DURATION = 86_400;
+
+// Program deploy qua upgradeable loader với `upgradeAuthority`, để initialize kiểm tra được ProgramData
+function upgradeableProgram(programId: PublicKey, upgradeAuthority: PublicKey): AddedAccount[] {
+  const [programData] = PublicKey.findProgramAddressSync([programId.toBuffer()], BPF_LOADER_UPGRADEABLE);
+  const program = Buffer.alloc(36);
+  program.writeUInt32LE(2, 0); // UpgradeableLoaderState::Program
+  programData.toBuffer().copy(program, 4);
+  const header = Buffer.alloc(45);
+  header.writeUInt32LE(3, 0); // UpgradeableLoaderState::ProgramData, slot 0
+  header.writeUInt8(1, 12); // Some(upgrade_authority)
+  upgradeAuthority.toBuffer().copy(header, 13);
+  const elf = fs.readFileSync("target/deploy/memewars.so");
+  return [
+    {
+      address: programId,
+      info: { lamports: LAMPORTS_PER_SOL, data: program, owner: BPF_LOADER_UPGRADEABLE, executable: true },
+    },
+    {
+      address: programData,
+      info: { lamports: 100 * LAMPORTS_PER_SOL, data: Buffer.concat([header, elf]), owner: BPF_LOADER_UPGRADEABLE, executable: false },
+    },
+  ];
+}
+
+// PriceUpdateV2 (VerificationLevel::Full), giá exponent -8
+function priceUpdate(feedId: number, price: number, publishTime: bigint, postedSlot = POSTED_SLOT_ALWAYS_FRESH) {
+  const data = Buffer.alloc(134);
+  PRICE_UPDATE_V2_DISCRIMINATOR.copy(data, 0);
+  data.writeUInt8(1, 40);
+  data.fill(feedId, 41, 73);
+  data.writeBigInt64LE(BigInt(price), 73);
+  data.writeBigUInt64LE(BigInt(0), 81);
+  data.writeInt32LE(-8, 89);
+  data.writeBigInt64LE(publishTime, 93);
+  data.writeBigInt64LE(publishTime, 101);
+  data.writeBigInt64LE(BigInt(price), 109);
+  data.writeBigUInt64LE(BigInt(0), 117);
+  data.writeBigUInt64LE(postedSlot, 125);
+  return { lamports: LAMPORTS_PER_SOL, data, owner: PYTH_RECEIVER, executable: false };
+}
+
+function funded(keypair: Keypair): AddedAccount {
+  return {
+    address: keypair.publicKey,
+    info: { lamports: 100 * LAMPORTS_PER_SOL, data: Buffer.alloc(0), owner: SystemProgram.programId, executable: false },
+  };
+}
+
+describe("MemeWars settlement (bankrun)", () => {
+  const admin = Keypair.generate();
+  const alice = Keypair.generate();
+  const bob = Keypair.generate();
+  const keeper = Keypair.generate();
+  const attestors = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+  const liveFeedA = Keypair.generate().publicKey;
+  const liveFeedB = Keypair.generate().publicKey;
+
+  let context: ProgramTestContext;
+  let program: Program<Memewars>;
+
+  const pda = (...seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+  const battleIdBytes = (battleId: anchor.BN) => battleId.toArrayLike(Buffer, "le", 8);
+  const battlePda = (battleId: anchor.BN) => pda(Buffer.from("battle"), battleIdBytes(battleId));
+  const vaultPda = (battleId: anchor.BN, team: number) =>
+    pda(Buffer.from("vault"), battleIdBytes(battleId), Buffer.from([team]));
+  const protocolStatePda = () => pda(Buffer.from("protocol_state"));
+  const treasuryPda = () => pda(Buffer.from("protocol_treasury"));
+  const insuranceFundPda = () => pda(Buffer.from("insurance_fund"));
+  const keeperSetPda = (authority: PublicKey) => pda(Buffer.from("keeper_set"), authority.toBuffer());
+  const attestationsPda = (battleId: anchor.BN) => pda(Buffer.from("attestations"), battleIdBytes(battleId));
+
+  async function warpTo(unixTimestamp: bigint) {
+    const clock = await context.banksClient.getClock();
+    context.setClock(
+      new Clock(clock.slot + BigInt(1), clock.epochStartTimestamp, clock.epoch, clock.leaderScheduleEpoch, unixTimestamp)
+    );
+  }
+
+  async function balance(address: PublicKey): Promise<number> {
+    return Number(await context.banksClient.getBalance(address));
+  }
+
+  async function addLamports(address: PublicKey, lamports: number) {
+    const account = await context.banksClient.getAccount(address);
+    context.setAccount(address, { ...account!, lamports: account!.lamports + lamports });
+  }
+
+  // Tạo battle head-to-head với feed A/B và mỗi phe một người chơi stake 1 SOL
+  async function createStakedBattle(battleId: anchor.BN, keeperSet: PublicKey | null = null) {
+    await program.methods
+      .createBattle(
+        battleId,
+        new anchor.BN(BATTLE_DURATION),
+        { handicapBps: new anchor.BN(0), tieToleranceBps: new anchor.BN(0), confidenceTies: false, scoringMode: 0 },
+        { startTime: new anchor.BN(0), preSeasonDeposits: false, lockPeriodSeconds: new anchor.BN(0) },
+        { minTeamStake: new anchor.BN(0), maxGrowthBps: new anchor.BN(0), maxEmaDivergenceBps: new anchor.BN(0) }
+      )
+      .accountsPartial({
+        authority: admin.publicKey,
+        battle: battlePda(battleId),
+        protocolState: protocolStatePda(),
+        tokenA: Keypair.generate().publicKey,
+        tokenB: Keypair.generate().publicKey,
+        priceFeedA: liveFeedA,
+        priceFeedB: liveFeedB,
+        keeperSet,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    for (const [player, team] of [[alice, TEAM_A], [bob, TEAM_B]] as const) {
+      const ticketAccount = Keypair.generate();
+      await program.methods
+        .deposit(new anchor.BN(LAMPORTS_PER_SOL), team)
+        .accountsPartial({
+          user: player.publicKey,
+          battle: battlePda(battleId),
+          protocolState: protocolStatePda(),
+          vault: vaultPda(battleId, team),
+          userTicketAccount: ticketAccount.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([player, ticketAccount])
+        .rpc();
+    }
+
+    return program.account.battleState.fetch(battlePda(battleId));
+  }
+
+  // Price update post sau end_time cho giá tại end_time
+  function postPriceUpdates(endTime: bigint, priceA: number, priceB: number) {
+    const updateA = Keypair.generate().publicKey;
+    const updateB = Keypair.generate().publicKey;
+    context.setAccount(updateA, priceUpdate(FEED_A, priceA, endTime));
+    context.setAccount(updateB, priceUpdate(FEED_B, priceB, endTime));
+    return { updateA, updateB };
+  }
+
+  before(async () => {
+    const programId = new PublicKey(IDL.address);
+    context = await start(
+      [],
+      [
+        ...upgradeableProgram(programId, admin.publicKey),
+        ...[admin, alice, bob, keeper, ...attestors].map(funded),
+        { address: liveFeedA, info: priceUpdate(FEED_A, 100_000_000, BigInt(0)) },
+        { address: liveFeedB, info: priceUpdate(FEED_B, 100_000_000, BigInt(0)) },
+      ]
+    );
+    program = new Program<Memewars>(IDL as Memewars, new BankrunProvider(context));
+
+    await program.methods
+      .initialize()
+      .accountsPartial({
+        authority: admin.publicKey,
+        program: programId,
+        programData: PublicKey.findProgramAddressSync([programId.toBuffer()], BPF_LOADER_UPGRADEABLE)[0],
+        protocolState: protocolStatePda(),
+        treasury: treasuryPda(),
+        insuranceFund: insuranceFundPda(),
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Lets a random signer propose from a later price update and pays the bounty out of the fee", async () => {
+    const battleId = new anchor.BN(1);
+    const battle = await createStakedBattle(battleId);
+    const endTime = BigInt(battle.endTime.toString());
+
+    // Lending trả 1 SOL yield vào vault A
+    await addLamports(vaultPda(battleId, TEAM_A), LAMPORTS_PER_SOL);
+
+    // Propose 2 giờ sau end_time bằng price update publish đúng end_time
+    await warpTo(endTime + BigInt(7_200));
+    const { updateA, updateB } = postPriceUpdates(endTime, 120_000_000, 100_000_000);
+    await program.methods
+      .proposeSettlement()
+      .accountsPartial({
+        proposer: keeper.publicKey,
+        battle: battlePda(battleId),
+        priceFeedA: updateA,
+        priceFeedB: updateB,
+      })
+      .signers([keeper])
+      .rpc();
+
+    const proposed = await program.account.battleState.fetch(battlePda(battleId));
+    assert.ok(proposed.phase.proposed);
+    assert.ok(proposed.settledBy.equals(keeper.publicKey));
+    assert.equal(proposed.winner, TEAM_A);
+
+    await warpTo(BigInt(proposed.disputeDeadline.toString()));
+    const keeperBefore = await balance(keeper.publicKey);
+    const feesBefore = (await balance(treasuryPda())) + (await balance(insuranceFundPda()));
+
+    await program.methods
+      .finalizeSettlement()
+      .accountsPartial({
+        finalizer: context.payer.publicKey,
+        battle: battlePda(battleId),
+        protocolState: protocolStatePda(),
+        treasury: treasuryPda(),
+        insuranceFund: insuranceFundPda(),
+        proposer: keeper.publicKey,
+        vaultA: vaultPda(battleId, TEAM_A),
+        vaultB: vaultPda(battleId, TEAM_B),
+      })
+      .rpc();
+
+    // Yield 1 SOL: fee 5% = 0.05 SOL, bounty = min(1% yield, 0.01 SOL, fee) = 0.01 SOL
+    const protocolFee = LAMPORTS_PER_SOL / 20;
+    const bounty = LAMPORTS_PER_SOL / 100;
+    const settled = await program.account.battleState.fetch(battlePda(battleId));
+    assert.ok(settled.phase.settled);
+    assert.equal(settled.keeperBounty.toNumber(), bounty);
+    assert.equal(settled.protocolFeeCollected.toNumber(), protocolFee - bounty);
+    assert.equal(settled.winnerYield.toNumber(), LAMPORTS_PER_SOL - protocolFee);
+    assert.equal((await balance(keeper.publicKey)) - keeperBefore, bounty);
+    assert.equal(
+      (await balance(treasuryPda())) + (await balance(insuranceFundPda())) - feesBefore,
+      protocolFee - bounty
+    );
+  });
+
+  it("Resolves a keeper-set battle once 2 of 3 keepers attest the same values", async () => {
+    const battleId = new anchor.BN(2);
+    await program.methods
+      .createKeeperSet(attestors.map((k) => k.publicKey), 2)
+      .accountsPartial({
+        authority: admin.publicKey,
+        keeperSet: keeperSetPda(admin.publicKey),
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+    const battle = await createStakedBattle(battleId, keeperSetPda(admin.publicKey));
+    await warpTo(BigInt(battle.endTime.toString()) + BigInt(60));
+
+    const attest = (attestor: Keypair, valueA: number, valueB: number) =>
+      program.methods
+        .attestResult(new anchor.BN(valueA), new anchor.BN(valueB))
+        .accountsPartial({
+          keeper: attestor.publicKey,
+          battle: battlePda(battleId),
+          keeperSet: keeperSetPda(admin.publicKey),
+          attestations: attestationsPda(battleId),
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([attestor])
+        .rpc();
+
+    // Hai keeper đầu attest khác nhau: chưa đủ threshold
+    await attest(attestors[0], 125_000_000, 98_000_000);
+    await attest(attestors[1], 125_000_000, 99_000_000);
+    let attested = await program.account.battleState.fetch(battlePda(battleId));
+    assert.ok(attested.phase.awaitingSettlement);
+
+    // Keeper thứ ba khớp với keeper đầu: 2/3 -> Proposed
+    await attest(attestors[2], 125_000_000, 98_000_000);
+    attested = await program.account.battleState.fetch(battlePda(battleId));
+    assert.ok(attested.phase.proposed);
+    assert.ok(attested.settledBy.equals(attestors[2].publicKey));
+    assert.equal(attested.winner, TEAM_A);
+  });
+
+  it("Covers a lending shortfall from the insurance fund before any haircut", async () => {
+    const battleId = new anchor.BN(3);
+    const battle = await createStakedBattle(battleId);
+    const endTime = BigInt(battle.endTime.toString());
+    const principal = 2 * LAMPORTS_PER_SOL;
+
+    await program.methods
+      .fundInsurance(new anchor.BN(LAMPORTS_PER_SOL))
+      .accountsPartial({
+        funder: admin.publicKey,
+        protocolState: protocolStatePda(),
+        insuranceFund: insuranceFundPda(),
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Coverage tối đa 10% principal, quỹ đủ lớn nên bù được trọn 10%
+    const coverage = await program.methods
+      .getInsuranceCoverage()
+      .accountsPartial({ battle: battlePda(battleId), protocolState: protocolStatePda() })
+      .view();
+    assert.equal(coverage.principal.toNumber(), principal);
+    assert.equal(coverage.coverageLimit.toNumber(), principal / 10);
+    assert.equal(coverage.coverageBps.toNumber(), 1_000);
+
+    // Lending trả thiếu 0.1 SOL ở vault B (trong coverage)
+    const shortfall = LAMPORTS_PER_SOL / 10;
+    await addLamports(vaultPda(battleId, TEAM_B), -shortfall);
+
+    await warpTo(endTime + BigInt(60));
+    const { updateA, updateB } = postPriceUpdates(endTime, 120_000_000, 100_000_000);
+    await program.methods
+      .proposeSettlement()
+      .accountsPartial({
+        proposer: keeper.publicKey,
+        battle: battlePda(battleId),
+        priceFeedA: updateA,
+        priceFeedB: updateB,
+      })
+      .signers([keeper])
+      .rpc();
+    const proposed = await program.account.battleState.fetch(battlePda(battleId));
+    await warpTo(BigInt(proposed.disputeDeadline.toString()));
+
+    const insuranceBefore = (await program.account.protocolState.fetch(protocolStatePda())).insuranceBalance;
+    const vaultBBefore = await balance(vaultPda(battleId, TEAM_B));
+    await program.methods
+      .finalizeSettlement()
+      .accountsPartial({
+        finalizer: context.payer.publicKey,
+        battle: battlePda(battleId),
+        protocolState: protocolStatePda(),
+        treasury: treasuryPda(),
+        insuranceFund: insuranceFundPda(),
+        proposer: keeper.publicKey,
+        vaultA: vaultPda(battleId, TEAM_A),
+        vaultB: vaultPda(battleId, TEAM_B),
+      })
+      .rpc();
+
+    // Insurance bù trọn thiếu hụt vào vault B: không haircut, không có yield
+    const settled = await program.account.battleState.fetch(battlePda(battleId));
+    assert.ok(settled.phase.settled);
+    assert.equal(settled.insurancePayout.toNumber(), shortfall);
+    assert.equal(settled.recoveryBps.toNumber(), 10_000);
+    assert.equal(settled.winnerYield.toNumber(), 0);
+    assert.equal((await balance(vaultPda(battleId, TEAM_B))) - vaultBBefore, shortfall);
+    const insuranceAfter = (await program.account.protocolState.fetch(protocolStatePda())).insuranceBalance;
+    assert.equal(insuranceBefore.sub(insuranceAfter).toNumber(), shortfall);
+  });
+});
@@ -476,12 +476,24 @@ export function useProgram() {
 // PYTH ORACLE CONSTANTS
 // ============================================================================
 
+export const PYTH_PUSH_ORACLE_PROGRAM_ID = new PublicKey("pythWSnswVUd12oZpeFP8e9CVaEqJg25g1Vtc2biRsT");
+
+// Price feed account (shard 0) của Pyth Push Oracle cho một feed ID
+export function pythPriceFeedAddress(feedId: string, shardId = 0): PublicKey {
+  const shard = Buffer.alloc(2);
+  shard.writeUInt16LE(shardId);
+  return PublicKey.findProgramAddressSync(
+    [shard, Buffer.from(feedId, "hex")],
+    PYTH_PUSH_ORACLE_PROGRAM_ID
+  )[0];
+}
+
 export const PYTH_PRICE_FEEDS = {
-  // Mainnet price feed IDs
-  SOL_USD: new PublicKey("H6ARHf6YXhGYeQfUzQNGk6rDNnLBQKrenN712K4AQJEG"),
-  BONK_USD: new PublicKey("8ihFLu5FimgTQ1Unh4dVyEHUGodJ5gJQCrQf4KUVB9bN"),
-  WIF_USD: new PublicKey("6ABgrEZk8urs6kJ1JNdC1sspH5zKXRqxy8sg3ZG2cQps"),
-  POPCAT_USD: new PublicKey("5SLwNNEXzgUJ4qjNYFKYxVKJVdrmMPdrP3sxyAj8UHbJ"),
+  // Mainnet price feeds (Pyth pull oracle, cùng feed ID trên mọi chain)
+  SOL_USD: pythPriceFeedAddress("ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d"),
+  BONK_USD: pythPriceFeedAddress("72b021217ca3fe68922a19aaf990109cb9d84e9ad004b4d2025ad6f529314419"),
+  WIF_USD: pythPriceFeedAddress("4ca4beeca86f0d164160323817a4e42b10010a724c2217c6ee41b54cd4cc61fc"),
+  POPCAT_USD: pythPriceFeedAddress("b9312a7ee50e189ef045aa3c7842e099b061bd9bdc99ac645956c3b660dc8cce"),
 };
 
 // Token mint addresses (mainnet)
@@ -31,11 +31,16 @@ export const TEAM = {
   B: 2,
 } as const;
 
-// Battle status
-export const BATTLE_STATUS = {
-  ACTIVE: 0,
-  SETTLED: 1,
-  CANCELLED: 2,
+// Battle phase (BattlePhase enum index)
+export const BATTLE_PHASE = {
+  SCHEDULED: 0,
+  OPEN: 1,
+  LOCKED: 2,
+  AWAITING_SETTLEMENT: 3,
+  PROPOSED: 4,
+  NEEDS_REVIEW: 5,
+  SETTLED: 6,
+  CANCELLED: 7,
 } as const;
 
 // Winner constants
@@ -127,7 +132,7 @@ export interface BattleState {
   endTime: bigint;
   totalStakedA: bigint;
   totalStakedB: bigint;
-  status: number;
+  phase: number;
   winner: number;
   vaultA: PublicKey;
   vaultB: PublicKey;
@@ -139,7 +144,9 @@ export interface UserState {
   battleId: bigint;
   team: number;
   amountStaked: bigint;
+  shares: bigint;
   stakeTime: bigint;
+  stakeSeconds: bigint;
   claimed: boolean;
   bump: number;
 }
@@ -148,6 +155,7 @@ export interface Vault {
   battleId: bigint;
   team: number;
   totalAmount: bigint;
+  totalShares: bigint;
   lentAmount: bigint;
   lendingPosition: PublicKey | null;
   bump: number;